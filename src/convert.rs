@@ -0,0 +1,324 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{interpreter::{Arity, Ctx, Error, Foreign, Value}, persistent};
+
+// Conversions between Rust types and Lispen values, so native functions can take and return plain Rust types:
+// numbers, strings, booleans, Vec, HashMap with string keys, Option (nil for None) and tuples (as lists or vectors).
+
+pub trait FromValue: Sized {
+    // What the value should have been, for error messages, e.g. "a number"
+    fn expected() -> String;
+
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+// What a typed native function can return: a value, or a Result to raise an error
+pub trait IntoNativeResult {
+    fn into_native_result(self) -> Result<Value, Rc<Error>>;
+}
+
+impl<T: IntoValue> IntoNativeResult for T {
+    fn into_native_result(self) -> Result<Value, Rc<Error>> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for Result<T, Rc<Error>> {
+    fn into_native_result(self) -> Result<Value, Rc<Error>> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+// A Rust function whose arguments are all FromValue, registered with 'Interpreter::register_fn'.
+// 'Args' is the tuple of its argument types, which only tells the implementations apart.
+pub trait TypedNative<Args> {
+    fn arity() -> Arity;
+
+    fn call_typed(&self, ctx: &mut Ctx, args: Vec<Value>) -> Result<Value, Rc<Error>>;
+}
+
+macro_rules! typed_native {
+    ($count:expr $(, $arg:ident $index:tt)*) => {
+        impl<F, R, $($arg),*> TypedNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R,
+            R: IntoNativeResult,
+            $($arg: FromValue),*
+        {
+            fn arity() -> Arity {
+                Arity::Exact($count)
+            }
+
+            #[allow(unused_variables)]
+            fn call_typed(&self, ctx: &mut Ctx, args: Vec<Value>) -> Result<Value, Rc<Error>> {
+                self($(ctx.arg::<$arg>(&args, $index)?),*).into_native_result()
+            }
+        }
+    };
+}
+
+typed_native!(0);
+typed_native!(1, A 0);
+typed_native!(2, A 0, B 1);
+typed_native!(3, A 0, B 1, C 2);
+typed_native!(4, A 0, B 1, C 2, D 3);
+typed_native!(5, A 0, B 1, C 2, D 3, E 4);
+
+// --- From values
+
+impl FromValue for Value {
+    fn expected() -> String {
+        "a value".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl FromValue for f64 {
+    fn expected() -> String {
+        "a number".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn expected() -> String {
+        "a number".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        f64::from_value(value).map(|n| n as f32)
+    }
+}
+
+// Integers only accept whole numbers that fit in the type.
+// 'MAX as f64' rounds up for 64 bit integers, so the upper bound is exclusive, one past it.
+macro_rules! from_value_integer {
+    ($($int:ty),*) => {
+        $(
+            impl FromValue for $int {
+                fn expected() -> String {
+                    format!("an integer between {} and {}", <$int>::MIN, <$int>::MAX)
+                }
+
+                fn from_value(value: &Value) -> Option<Self> {
+                    match value {
+                        Value::Number(n) if n.fract() == 0.0 && *n >= <$int>::MIN as f64 && *n < <$int>::MAX as f64 + 1.0 => Some(*n as $int),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_value_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromValue for String {
+    fn expected() -> String {
+        "a string".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn expected() -> String {
+        "a boolean".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            other => T::from_value(other).map(Some),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn expected() -> String {
+        format!("a list of elements that are each {}", T::expected())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(l) => l.iter().map(T::from_value).collect(),
+            Value::Vector(v) => v.iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn expected() -> String {
+        format!("a map from strings to values that are each {}", T::expected())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Map(m) => m.iter().map(|(k, v)| Some((String::from_value(k)?, T::from_value(v)?))).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Rc<Foreign> {
+    fn expected() -> String {
+        "a foreign object".to_owned()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Foreign(o) => Some(o.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Tuples are read from lists or vectors with exactly as many elements
+macro_rules! from_value_tuple {
+    ($count:expr, $($arg:ident $index:tt),*) => {
+        impl<$($arg: FromValue),*> FromValue for ($($arg,)*) {
+            fn expected() -> String {
+                let types: Vec<String> = vec![$($arg::expected()),*];
+                format!("a list of {} elements ({})", $count, types.join(", "))
+            }
+
+            fn from_value(value: &Value) -> Option<Self> {
+                let items = match value {
+                    Value::List(_) | Value::Vector(_) => value.elements()?,
+                    _ => { return None; }
+                };
+
+                if items.len() != $count {
+                    return None;
+                }
+
+                Some(($($arg::from_value(&items[$index])?,)*))
+            }
+        }
+    };
+}
+
+from_value_tuple!(1, A 0);
+from_value_tuple!(2, A 0, B 1);
+from_value_tuple!(3, A 0, B 1, C 2);
+from_value_tuple!(4, A 0, B 1, C 2, D 3);
+
+// --- Into values
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for Foreign {
+    fn into_value(self) -> Value {
+        Value::Foreign(Rc::new(self))
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+macro_rules! into_value_number {
+    ($($num:ty),*) => {
+        $(
+            impl IntoValue for $num {
+                fn into_value(self) -> Value {
+                    Value::Number(self as f64)
+                }
+            }
+        )*
+    };
+}
+
+into_value_number!(f32, f64, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.to_owned())
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::Vector(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        Value::Map(self.into_iter().map(|(k, v)| (Value::String(k), v.into_value())).collect::<persistent::Map<_, _>>())
+    }
+}
+
+macro_rules! into_value_tuple {
+    ($($arg:ident $index:tt),*) => {
+        impl<$($arg: IntoValue),*> IntoValue for ($($arg,)*) {
+            fn into_value(self) -> Value {
+                Value::Vector(persistent::Vector::from_iter([$(self.$index.into_value()),*]))
+            }
+        }
+    };
+}
+
+into_value_tuple!(A 0);
+into_value_tuple!(A 0, B 1);
+into_value_tuple!(A 0, B 1, C 2);
+into_value_tuple!(A 0, B 1, C 2, D 3);
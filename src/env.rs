@@ -1,26 +1,193 @@
-use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}, process::Command, io::Write};
+use std::{cell::{Cell, RefCell}, collections::HashMap, ffi::OsStr, fs, time::{SystemTime, UNIX_EPOCH}, path::{Path, PathBuf}, process::Command, io::{Read, Write}, rc::{Rc, Weak}};
 
-use crate::interpreter::{Value, Function};
+use crate::{interpreter::{self, Arity, Ctx, Error, Foreign, Function, Value}, module, persistent, prelude, process};
 
+// What a script is allowed to reach outside the interpreter. A global environment only gets the native functions
+// of the capabilities it was created with, so untrusted scripts can run with none of them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Capability {
+  // running other programs
+  Process,
+  // reading and writing files, including importing modules
+  Filesystem,
+  // reading the standard input
+  Stdin,
+  // reading the clock
+  Time,
+  // reading and changing environment variables
+  Env,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+  pub const NONE: Capabilities = Capabilities(0);
+  pub const ALL: Capabilities = Capabilities(0b11111);
+
+  pub fn with(self, capability: Capability) -> Capabilities {
+    Capabilities(self.0 | Self::bit(capability))
+  }
+
+  pub fn without(self, capability: Capability) -> Capabilities {
+    Capabilities(self.0 & !Self::bit(capability))
+  }
+
+  pub fn has(self, capability: Capability) -> bool {
+    self.0 & Self::bit(capability) != 0
+  }
+
+  fn bit(capability: Capability) -> u8 {
+    match capability {
+      Capability::Process => 1,
+      Capability::Filesystem => 2,
+      Capability::Stdin => 4,
+      Capability::Time => 8,
+      Capability::Env => 16,
+    }
+  }
+}
+
+impl Default for Capabilities {
+  fn default() -> Self {
+    Capabilities::ALL
+  }
+}
+
+// Environments are shared: cloning one (e.g. when a function captures its scope)
+// gives another handle to the same variables.
+// A function stored in the scope it captures makes a cycle, which 'collect_cycles' frees.
 #[derive(Clone)]
 pub struct Environment {
+  scope: Rc<RefCell<Scope>>,
+  program: Rc<Program>,
+}
+
+// What every scope of a program shares, including the global environments of the modules it imports,
+// so two interpreters never see each other's modules
+struct Program {
+  capabilities: Capabilities,
+  // whether the global environments of its modules get the prelude too
+  prelude: bool,
+  modules: RefCell<module::Modules>,
+  vars: Rc<EnvVars>,
+}
+
+// The environment variables a program sees: those of the process, with the changes its 'setenv' calls made.
+// The process's own aren't changed, since that isn't safe while another thread may be reading them,
+// so the changes are kept here and given to the programs it runs.
+#[derive(Default)]
+pub struct EnvVars {
+  // None for a variable that was unset
+  changes: RefCell<HashMap<String, Option<String>>>,
+}
+
+impl EnvVars {
+  pub fn get(&self, name: &str) -> Option<String> {
+    match self.changes.borrow().get(name) {
+      Some(value) => value.clone(),
+      None => std::env::var(name).ok(),
+    }
+  }
+
+  pub fn set(&self, name: String, value: Option<String>) {
+    self.changes.borrow_mut().insert(name, value);
+  }
+
+  // Every variable whose name and value are valid unicode
+  pub fn all(&self) -> HashMap<String, String> {
+    let mut vars: HashMap<String, String> = std::env::vars_os()
+      .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+      .collect();
+
+    for (name, value) in self.changes.borrow().iter() {
+      match value {
+        Some(value) => vars.insert(name.clone(), value.clone()),
+        None => vars.remove(name),
+      };
+    }
+
+    vars
+  }
+
+  // Makes a program started with 'command' see the changes
+  pub fn apply(&self, command: &mut Command) {
+    for (name, value) in self.changes.borrow().iter() {
+      match value {
+        Some(value) => command.env(name, value),
+        None => command.env_remove(name),
+      };
+    }
+  }
+}
+
+struct Scope {
   values: HashMap<String, Value>,
-  enclosing: Option<Box<Environment>>,
+  enclosing: Option<Environment>,
+}
+
+impl Scope {
+  fn new(values: HashMap<String, Value>, enclosing: Option<Environment>) -> Rc<RefCell<Scope>> {
+    #[cfg(test)]
+    tests::LIVE_SCOPES.with(|n| n.set(n.get() + 1));
+
+    Rc::new(RefCell::new(Scope { values, enclosing }))
+  }
+
+  // Every cycle goes through a local scope holding a user function
+  fn may_be_in_cycle(&self) -> bool {
+    self.enclosing.is_some() && self.values.values().any(|v| matches!(v, Value::Function(f) if matches!(**f, Function::UserFn { .. })))
+  }
+}
+
+#[cfg(test)]
+impl Drop for Scope {
+  fn drop(&mut self) {
+    tests::LIVE_SCOPES.with(|n| n.set(n.get() - 1));
+  }
 }
 
 impl Environment {
-  pub fn new() -> Self {
-    Self {
-      values: hashmap_with_native_fns(),
-      enclosing: None,
+  // A global environment, with the prelude and the native functions of the given capabilities
+  pub fn new(capabilities: Capabilities) -> Self {
+    Self::global(Rc::new(Program { capabilities, prelude: true, modules: RefCell::default(), vars: Rc::default() }))
+  }
+
+  // A global environment with only the native functions
+  pub fn without_prelude(capabilities: Capabilities) -> Self {
+    Self::global(Rc::new(Program { capabilities, prelude: false, modules: RefCell::default(), vars: Rc::default() }))
+  }
+
+  // A new global environment in the same program, for a module it imports
+  pub fn for_module(&self) -> Self {
+    Self::global(self.program.clone())
+  }
+
+  fn global(program: Rc<Program>) -> Self {
+    let mut values = hashmap_with_native_fns(program.capabilities, &program.vars);
+
+    if program.prelude {
+      values.extend(prelude::definitions());
     }
+
+    Self { scope: Scope::new(values, None), program }
   }
 
   pub fn from_enclosing(enclosing: Environment) -> Self {
-    Self {
-      values: HashMap::new(),
-      enclosing: Some(Box::new(enclosing)),
+    if CANDIDATES.with(|c| c.borrow().len()) >= COLLECT_AT.with(Cell::get) {
+      collect_cycles();
     }
+
+    Self { program: enclosing.program.clone(), scope: Scope::new(HashMap::new(), Some(enclosing)) }
+  }
+
+  pub fn capabilities(&self) -> Capabilities {
+    self.program.capabilities
+  }
+
+  // The modules loaded by the program, and where it looks for them
+  pub fn modules(&self) -> &RefCell<module::Modules> {
+    &self.program.modules
   }
 
   // ---
@@ -28,132 +195,1117 @@ impl Environment {
   /*
   // For debugging. Uncomment when necessary.
   pub fn print_variables(&self) {
-    for (k, v) in self.values.iter() {
+    for (k, v) in self.scope.borrow().values.iter() {
       println!("{}: {}", k, v);
     }
   }
   */
 
   pub fn define_variable(&mut self, name: String, value: Value) {
-    self.values.insert(name, value);
+    self.scope.borrow_mut().values.insert(name, value);
+  }
+
+  // Changes the value of an existing variable, in whichever scope it was defined.
+  // Returns false if there's no such variable.
+  pub fn assign_variable(&mut self, name: &str, value: Value) -> bool {
+    let mut scope = self.scope.borrow_mut();
+
+    if let Some(v) = scope.values.get_mut(name) {
+      *v = value;
+      return true;
+    }
+
+    match scope.enclosing.as_mut() {
+      Some(e) => e.assign_variable(name, value),
+      None => false,
+    }
   }
 
+  // Also resolves 'module/name', looking 'name' up in the exports of an imported module,
+  // and 'object/name', the method 'name' of a foreign object.
   pub fn get_variable(&self, name: &str) -> Option<Value> {
-    let res = self.values.get(name).cloned();
+    if let Some(value) = self.lookup(name) {
+      return Some(value);
+    }
+
+    let (module, member) = name.split_once('/')?;
+
+    match self.lookup(module)? {
+      Value::Map(exports) if !member.is_empty() => exports.get(&Value::String(member.to_owned())).cloned(),
+      Value::Foreign(object) => Foreign::bound_method(&object, member),
+      _ => None,
+    }
+  }
+
+  fn lookup(&self, name: &str) -> Option<Value> {
+    let scope = self.scope.borrow();
+
+    match scope.values.get(name) {
+      Some(v) => Some(v.clone()),
+      None => scope.enclosing.as_ref()?.lookup(name),
+    }
+  }
+
+  // The variables defined in this scope itself, without the enclosing ones
+  pub fn own_variables(&self) -> Vec<(String, Value)> {
+    self.scope.borrow().values.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+  }
+}
+
+impl Drop for Environment {
+  fn drop(&mut self) {
+    // a local scope that still has other handles may only be kept alive by functions stored in it
+    if Rc::strong_count(&self.scope) > 1 && self.scope.try_borrow().map_or(true, |s| s.may_be_in_cycle()) {
+      let _ = CANDIDATES.try_with(|c| {
+        if let Ok(mut candidates) = c.try_borrow_mut() {
+          candidates.insert(Rc::as_ptr(&self.scope), Rc::downgrade(&self.scope));
+        }
+      });
+    }
+  }
+}
+
+// --- Cycles
+
+// How many candidates there must be before they're collected, at least
+const MIN_CANDIDATES: usize = 1024;
+
+thread_local! {
+  // scopes that lost a handle but not their last one, which may be part of a cycle
+  static CANDIDATES: RefCell<HashMap<*const RefCell<Scope>, Weak<RefCell<Scope>>>> = RefCell::new(HashMap::new());
+  static COLLECT_AT: Cell<usize> = const { Cell::new(MIN_CANDIDATES) };
+}
+
+// A scope or a user function found while looking for cycles
+enum Node {
+  Scope(Rc<RefCell<Scope>>),
+  Function(Rc<Function>),
+}
+
+impl Node {
+  fn strong_count(&self) -> usize {
+    match self {
+      Node::Scope(s) => Rc::strong_count(s),
+      Node::Function(f) => Rc::strong_count(f),
+    }
+  }
+}
+
+// The scopes and functions reachable from the candidates, and the references between them
+#[derive(Default)]
+struct Graph {
+  nodes: Vec<Node>,
+  index: HashMap<*const (), usize>,
+  // how many references to each node come from other nodes
+  internal: Vec<usize>,
+  edges: Vec<Vec<usize>>,
+}
+
+impl Graph {
+  // The index of a node, adding it (and a handle to it) the first time it's seen
+  fn node(&mut self, key: *const (), node: impl FnOnce() -> Node, pending: &mut Vec<usize>) -> usize {
+    if let Some(&i) = self.index.get(&key) {
+      return i;
+    }
+
+    self.nodes.push(node());
+    self.internal.push(0);
+    self.edges.push(Vec::new());
+    self.index.insert(key, self.nodes.len() - 1);
+    pending.push(self.nodes.len() - 1);
 
-    if res.is_none() {
-      match self.enclosing.clone() {
-        Some(e) => e.get_variable(name),
-        None => None,
+    self.nodes.len() - 1
+  }
+
+  fn scope(&mut self, scope: &Rc<RefCell<Scope>>, pending: &mut Vec<usize>) -> usize {
+    self.node(Rc::as_ptr(scope) as *const (), || Node::Scope(scope.clone()), pending)
+  }
+
+  fn edge(&mut self, from: usize, to: usize) {
+    self.internal[to] += 1;
+    self.edges[from].push(to);
+  }
+}
+
+// Frees the scopes that are only referenced by functions stored in them (or in scopes inside them), e.g. after
+// '(let [f (fn ...)] ...)' or a 'defn' in a function. A node is alive if it has more references than the graph accounts for,
+// which come from the interpreter or from anything else, or if it's reachable from one that is; the scopes
+// of the rest are emptied, breaking their cycles.
+// Collections aren't looked into, so a function in a collection keeps its scope alive.
+pub fn collect_cycles() {
+  let candidates = CANDIDATES.with(|c| std::mem::take(&mut *c.borrow_mut()));
+
+  let mut graph = Graph::default();
+  let mut pending = Vec::new();
+
+  for scope in candidates.values().filter_map(Weak::upgrade) {
+    graph.scope(&scope, &mut pending);
+  }
+
+  let roots = graph.nodes.len();
+
+  while let Some(i) = pending.pop() {
+    match &graph.nodes[i] {
+      Node::Scope(scope) => {
+        let scope = scope.clone();
+
+        // a scope being changed right now is in use, so nothing is collected this time
+        let Ok(scope) = scope.try_borrow() else {
+          CANDIDATES.with(|c| c.borrow_mut().extend(candidates));
+          return;
+        };
+
+        // a global scope is kept by its interpreter or module, so what it holds isn't looked into
+        let Some(enclosing) = scope.enclosing.clone() else {
+          continue;
+        };
+
+        let functions: Vec<Rc<Function>> = scope.values.values().filter_map(|value| match value {
+          Value::Function(f) if matches!(**f, Function::UserFn { .. }) => Some(f.clone()),
+          _ => None,
+        }).collect();
+
+        drop(scope);
+
+        for f in functions {
+          let to = graph.node(Rc::as_ptr(&f) as *const (), || Node::Function(f.clone()), &mut pending);
+          graph.edge(i, to);
+        }
+
+        let to = graph.scope(&enclosing.scope, &mut pending);
+        graph.edge(i, to);
+      }
+
+      Node::Function(f) => {
+        if let Function::UserFn { env, .. } = &**f {
+          let scope = env.scope.clone();
+          let to = graph.scope(&scope, &mut pending);
+          graph.edge(i, to);
+        }
       }
     }
-    else {
-      res
+  }
+
+  // the graph itself holds one handle to each node, and the values cloned above are gone
+  let mut alive = vec![false; graph.nodes.len()];
+  let mut reachable: Vec<usize> = (0..graph.nodes.len()).filter(|&i| graph.nodes[i].strong_count() > graph.internal[i] + 1).collect();
+
+  while let Some(i) = reachable.pop() {
+    if !alive[i] {
+      alive[i] = true;
+      reachable.extend(graph.edges[i].iter().copied());
+    }
+  }
+
+  let mut garbage = Vec::new();
+  let mut survivors = HashMap::new();
+
+  for (i, node) in graph.nodes.iter().enumerate() {
+    match node {
+      Node::Scope(scope) if !alive[i] => garbage.push(std::mem::take(&mut scope.borrow_mut().values)),
+      // a candidate still alive stays one, since what keeps it alive now may not later
+      Node::Scope(scope) if i < roots => { survivors.insert(Rc::as_ptr(scope), Rc::downgrade(scope)); }
+      _ => {}
     }
   }
+
+  COLLECT_AT.with(|c| c.set(MIN_CANDIDATES.max(survivors.len() * 2)));
+  CANDIDATES.with(|c| c.borrow_mut().extend(survivors));
+
+  drop(graph);
+  drop(garbage);
+}
+
+thread_local! {
+  // what 'args' returns, set by the host program
+  static ARGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+// The arguments scripts see with 'args'
+pub fn set_args(args: Vec<String>) {
+  ARGS.with(|a| *a.borrow_mut() = args);
+}
+
+// The native functions, leaving out the ones that need a capability that isn't enabled
+fn hashmap_with_native_fns(capabilities: Capabilities, vars: &Rc<EnvVars>) -> HashMap<String, Value> {
+  let mut natives = core_fns();
+
+  if capabilities.has(Capability::Process) {
+    natives.extend(process_fns(vars.clone()));
+  }
+
+  if capabilities.has(Capability::Filesystem) {
+    natives.extend(filesystem_fns());
+  }
+
+  if capabilities.has(Capability::Stdin) {
+    natives.extend(stdin_fns());
+  }
+
+  if capabilities.has(Capability::Time) {
+    natives.extend(time_fns());
+  }
+
+  if capabilities.has(Capability::Env) {
+    natives.extend(env_var_fns(vars.clone()));
+  }
+
+  natives
 }
 
-fn hashmap_with_native_fns() -> HashMap<String, Value> {
+// The functions any script can use: they only work on values, and print to the standard output
+fn core_fns() -> HashMap<String, Value> {
   HashMap::from([
     // -- Prelude --
 
-    ("println".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
+    native("println", Arity::Exact(1), "Prints a value, followed by a new line", |_, args| {
       println!("{}", args[0]);
-      Some(Value::Nil)
-    } })),
+      Ok(Value::Nil)
+    }),
 
-    ("print".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
+    native("print", Arity::Exact(1), "Prints a value", |_, args| {
       print!("{}", args[0]);
-      Some(Value::Nil)
-    } })),
+      Ok(Value::Nil)
+    }),
 
-    ("println_blank".into(), Value::Function(Function::NativeFn { arity: 0, call: |_| {
+    native("println_blank", Arity::Exact(0), "Prints an empty line", |_, _| {
       println!();
-      Some(Value::Nil)
-    } })),
+      Ok(Value::Nil)
+    }),
+
+    native("doc", Arity::Exact(1), "The documentation of a native function, or nil", |_, args| {
+      match &args[0] {
+        Value::Function(f) => match &**f {
+          Function::NativeFn(native) => Ok(Value::String(native.doc.clone())),
+          _ => Ok(Value::Nil),
+        },
+        _ => Ok(Value::Nil),
+      }
+    }),
 
-    ("time_ms".into(), Value::Function(Function::NativeFn { arity: 0, call: |_| {
-      Some(Value::Number(SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis() as f64))
-    } })),
+    // -- Comparison --
 
-    ("time_sec".into(), Value::Function(Function::NativeFn { arity: 0, call: |_| {
-      Some(Value::Number(SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as f64))
-    } })),
+    native("equal?", Arity::Exact(2), "Whether two values are structurally equal, like '='", |_, args| {
+      Ok(Value::Bool(args[0] == args[1]))
+    }),
 
-    ("execute".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
-      if let Value::String(s) = args[0].clone() {
-        let output = if cfg!(target_os = "windows") {
-          Command::new("cmd")
-                  .arg("/c")
-                  .arg(s)
-                  .output()
-                  .ok()?
+    native("eq?", Arity::Exact(2), "Whether two values are the very same object", |_, args| {
+      Ok(Value::Bool(args[0].is_identical(&args[1])))
+    }),
+
+    native("compare", Arity::Exact(2), "-1, 0 or 1, depending on how two values are ordered (values of different types by type)", |_, args| {
+      Ok(Value::Number(args[0].cmp(&args[1]) as i8 as f64))
+    }),
+
+    native("sort", Arity::Exact(1), "Sorts a collection, using 'compare'", |ctx, args| {
+      let mut items = elements(&args[0], "sort", ctx)?;
+      items.sort();
+
+      match &args[0] {
+        Value::Vector(_) => Ok(Value::Vector(items.into_iter().collect())),
+        _ => Ok(Value::List(items.into_iter().collect())),
+      }
+    }),
+
+    // -- Maps --
+
+    native("get", Arity::Exact(2), "The value of a key in a map, an index in a vector or a field in a record, or nil", |ctx, args| {
+      match &args[0] {
+        Value::Vector(v) => Ok(v.get(expect_index(&args[1], ctx)?).cloned().unwrap_or(Value::Nil)),
+        Value::Set(s) => Ok(if s.contains(&args[1]) { args[1].clone() } else { Value::Nil }),
+        Value::Record(r) => Ok(r.values[expect_field(r, &args[1], ctx)?].clone()),
+
+        _ => {
+          let map = expect_map(&args[0], "get", ctx)?;
+          Ok(map.get(&args[1]).cloned().unwrap_or(Value::Nil))
         }
-        else {
-          Command::new("sh")
-                  .arg("-c")
-                  .arg(s)
-                  .output()
-                  .ok()?
+      }
+    }),
+
+    native("assoc", Arity::Exact(3), "Binds a key to a value in a map, an index in a vector or a field in a record", |ctx, args| {
+      if let Value::Vector(v) = &args[0] {
+        let index = expect_index(&args[1], ctx)?;
+
+        if index == v.len() {
+          return Ok(Value::Vector(v.push_back(args[2].clone())));
+        }
+
+        return match v.update(index, args[2].clone()) {
+          Some(v) => Ok(Value::Vector(v)),
+          None => ctx.error("index-error", &format!("Index {} out of bounds for vector of length {}", index, v.len())),
         };
-  
-        Some(Value::String(String::from_utf8_lossy(output.stdout.as_slice()).into_owned()))
       }
-      else {
-        None
+
+      if let Value::Record(r) = &args[0] {
+        return Ok(Value::Record(r.with(expect_field(r, &args[1], ctx)?, args[2].clone())));
       }
-    } })),
 
-    ("execute_exit_code".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
-      if let Value::String(s) = args[0].clone() {
-        let output = if cfg!(target_os = "windows") {
-          Command::new("cmd")
-                  .arg("/c")
-                  .arg(s)
-                  .output()
-                  .ok()?
+      let map = expect_map(&args[0], "assoc", ctx)?;
+      Ok(Value::Map(map.insert(args[1].clone(), args[2].clone())))
+    }),
+
+    native("dissoc", Arity::Exact(2), "Removes a key from a map", |ctx, args| {
+      let map = expect_map(&args[0], "dissoc", ctx)?;
+      Ok(Value::Map(map.remove(&args[1])))
+    }),
+
+    native("keys", Arity::Exact(1), "The keys of a map, in order", |ctx, args| {
+      let map = expect_map(&args[0], "keys", ctx)?;
+      Ok(Value::List(map.sorted().into_iter().map(|(k, _)| k.clone()).collect()))
+    }),
+
+    native("values", Arity::Exact(1), "The values of a map, ordered by their keys", |ctx, args| {
+      let map = expect_map(&args[0], "values", ctx)?;
+      Ok(Value::List(map.sorted().into_iter().map(|(_, v)| v.clone()).collect()))
+    }),
+
+    native("contains?", Arity::Exact(2), "Whether a map has a key, or a set has an element", |ctx, args| {
+      if let Value::Set(s) = &args[0] {
+        return Ok(Value::Bool(s.contains(&args[1])));
+      }
+
+      let map = expect_map(&args[0], "contains?", ctx)?;
+      Ok(Value::Bool(map.contains_key(&args[1])))
+    }),
+
+    native("merge", Arity::Exact(2), "Combines two maps, the values of the second taking precedence", |ctx, args| {
+      let mut map = expect_map(&args[0], "merge", ctx)?;
+      let other = expect_map(&args[1], "merge", ctx)?;
+
+      map.extend(other.iter().map(|(k, v)| (k.clone(), v.clone())));
+      Ok(Value::Map(map))
+    }),
+
+    // (update map key fn) calls 'fn' with the current value (or nil) and stores the result
+    native("update", Arity::Exact(3), "Binds a key to the result of calling a function with its current value", |ctx, args| {
+      if let Value::Record(r) = &args[0] {
+        let index = expect_field(r, &args[1], ctx)?;
+        let new = ctx.call(args[2].clone(), vec![r.values[index].clone()])?;
+
+        return Ok(Value::Record(r.with(index, new)));
+      }
+
+      let map = expect_map(&args[0], "update", ctx)?;
+      let current = map.get(&args[1]).cloned().unwrap_or(Value::Nil);
+
+      let new = ctx.call(args[2].clone(), vec![current])?;
+      Ok(Value::Map(map.insert(args[1].clone(), new)))
+    }),
+
+    // -- Sets --
+
+    native("union", Arity::Exact(2), "The elements in either set", |ctx, args| {
+      let a = expect_set(&args[0], "union", ctx)?;
+      let b = expect_set(&args[1], "union", ctx)?;
+
+      Ok(Value::Set(a.union(&b)))
+    }),
+
+    native("intersection", Arity::Exact(2), "The elements in both sets", |ctx, args| {
+      let a = expect_set(&args[0], "intersection", ctx)?;
+      let b = expect_set(&args[1], "intersection", ctx)?;
+
+      Ok(Value::Set(a.intersection(&b)))
+    }),
+
+    native("difference", Arity::Exact(2), "The elements of the first set that aren't in the second", |ctx, args| {
+      let a = expect_set(&args[0], "difference", ctx)?;
+      let b = expect_set(&args[1], "difference", ctx)?;
+
+      Ok(Value::Set(a.difference(&b)))
+    }),
+
+    native("subset?", Arity::Exact(2), "Whether every element of the first set is in the second", |ctx, args| {
+      let a = expect_set(&args[0], "subset?", ctx)?;
+      let b = expect_set(&args[1], "subset?", ctx)?;
+
+      Ok(Value::Bool(a.is_subset(&b)))
+    }),
+
+    native("superset?", Arity::Exact(2), "Whether every element of the second set is in the first", |ctx, args| {
+      let a = expect_set(&args[0], "superset?", ctx)?;
+      let b = expect_set(&args[1], "superset?", ctx)?;
+
+      Ok(Value::Bool(b.is_subset(&a)))
+    }),
+
+    native("disj", Arity::Exact(2), "Removes an element from a set", |ctx, args| {
+      let set = expect_set(&args[0], "disj", ctx)?;
+      Ok(Value::Set(set.remove(&args[1])))
+    }),
+
+    // -- Collections --
+    // These work on lists, vectors, sets and maps alike; maps are seen as a sequence of [key value] vectors.
+
+    native("count", Arity::Exact(1), "The number of elements in a collection, or characters in a string", |ctx, args| {
+      let count = match &args[0] {
+        Value::String(s) => s.chars().count(),
+        Value::List(l) => l.len(),
+        Value::Vector(v) => v.len(),
+        Value::Map(m) => m.len(),
+        Value::Set(s) => s.len(),
+        Value::Nil => 0,
+
+        other => { return ctx.error("type-error", &format!("Function 'count' expected a collection, got '{}'", other)); }
+      };
+
+      Ok(Value::Number(count as f64))
+    }),
+
+    native("empty?", Arity::Exact(1), "Whether a collection or a string is empty", |ctx, args| {
+      let empty = match &args[0] {
+        Value::String(s) => s.is_empty(),
+        Value::List(l) => l.is_empty(),
+        Value::Vector(v) => v.is_empty(),
+        Value::Map(m) => m.is_empty(),
+        Value::Set(s) => s.is_empty(),
+        Value::Nil => true,
+
+        other => { return ctx.error("type-error", &format!("Function 'empty?' expected a collection, got '{}'", other)); }
+      };
+
+      Ok(Value::Bool(empty))
+    }),
+
+    native("first", Arity::Exact(1), "The first element of a collection, or nil", |ctx, args| {
+      match &args[0] {
+        Value::List(l) => Ok(l.first().cloned().unwrap_or(Value::Nil)),
+        Value::Vector(v) => Ok(v.get(0).cloned().unwrap_or(Value::Nil)),
+        other => Ok(elements(other, "first", ctx)?.into_iter().next().unwrap_or(Value::Nil)),
+      }
+    }),
+
+    native("rest", Arity::Exact(1), "Every element of a collection but the first", |ctx, args| {
+      match &args[0] {
+        Value::List(l) => Ok(Value::List(l.rest())),
+        Value::Vector(v) => Ok(Value::Vector(v.iter().skip(1).cloned().collect())),
+        other => Ok(Value::List(elements(other, "rest", ctx)?.into_iter().skip(1).collect())),
+      }
+    }),
+
+    native("nth", Arity::Exact(2), "The element at an index of a list or a vector", |ctx, args| {
+      let index = expect_index(&args[1], ctx)?;
+
+      let (item, len) = match &args[0] {
+        Value::List(l) => (l.get(index), l.len()),
+        Value::Vector(v) => (v.get(index), v.len()),
+        other => { return ctx.error("type-error", &format!("Function 'nth' expected a list or a vector, got '{}'", other)); }
+      };
+
+      match item {
+        Some(v) => Ok(v.clone()),
+        None => ctx.error("index-error", &format!("Index {} out of bounds for collection of length {}", index, len))
+      }
+    }),
+
+    // Adds an element where it's cheapest for each collection: lists at the front, vectors at the end
+    native("conj", Arity::Exact(2), "Adds an element to a collection", |ctx, args| {
+      match &args[0] {
+        Value::List(l) => Ok(Value::List(l.cons(args[1].clone()))),
+        Value::Vector(v) => Ok(Value::Vector(v.push_back(args[1].clone()))),
+        Value::Set(s) => Ok(Value::Set(s.insert(args[1].clone()))),
+        Value::Map(m) => {
+          let (key, value) = expect_entry(&args[1], ctx)?;
+          Ok(Value::Map(m.insert(key, value)))
         }
-        else {
-          Command::new("sh")
-                  .arg("-c")
-                  .arg(s)
-                  .output()
-                  .ok()?
-        };
+
+        other => ctx.error("type-error", &format!("Function 'conj' expected a collection, got '{}'", other))
+      }
+    }),
+
+    native("to-list", Arity::Exact(1), "The elements of a collection, as a list", |ctx, args| {
+      Ok(Value::List(elements(&args[0], "to-list", ctx)?.into_iter().collect()))
+    }),
+
+    native("to-vector", Arity::Exact(1), "The elements of a collection, as a vector", |ctx, args| {
+      Ok(Value::Vector(elements(&args[0], "to-vector", ctx)?.into_iter().collect()))
+    }),
+
+    native("to-set", Arity::Exact(1), "The elements of a collection, as a set", |ctx, args| {
+      Ok(Value::Set(elements(&args[0], "to-set", ctx)?.into_iter().collect()))
+    }),
+
+    native("map", Arity::Exact(2), "Calls a function with every element of a collection, collecting the results", |ctx, args| {
+      let mut results = Vec::new();
+
+      for item in elements(&args[1], "map", ctx)? {
+        results.push(ctx.call(args[0].clone(), vec![item])?);
+      }
+
+      // mapping over a map produces arbitrary values, so the result is a list
+      match &args[1] {
+        Value::Map(_) => Ok(Value::List(results.into_iter().collect())),
+        other => collect_like(other, results, ctx),
+      }
+    }),
+
+    native("filter", Arity::Exact(2), "The elements of a collection for which a function returns a truthy value", |ctx, args| {
+      let mut results = Vec::new();
+
+      for item in elements(&args[1], "filter", ctx)? {
+        if ctx.call(args[0].clone(), vec![item.clone()])?.is_truthy() {
+          results.push(item);
+        }
+      }
+
+      collect_like(&args[1], results, ctx)
+    }),
+
+    // (reduce fn initial coll)
+    native("reduce", Arity::Exact(3), "Combines the elements of a collection with a function, starting from an initial value", |ctx, args| {
+      let mut acc = args[1].clone();
+
+      for item in elements(&args[2], "reduce", ctx)? {
+        acc = ctx.call(args[0].clone(), vec![acc, item])?;
+      }
+
+      Ok(acc)
+    }),
+
+    // -- Program --
+
+    native("args", Arity::Exact(0), "The arguments the script was run with, as a list of strings", |_, _| {
+      Ok(Value::List(ARGS.with(|args| args.borrow().iter().cloned().map(Value::String).collect())))
+    }),
+
+    // runs the 'finally' blocks on the way out, but can't be caught
+    native("exit", Arity::Between(0, 1), "Ends the script, with an exit code (0 by default)", |ctx, args| {
+      let code: Option<i32> = ctx.arg(&args, 0)?;
+      ctx.exit(code.unwrap_or(0))
+    }),
+
+    // -- Paths --
+    // these only work on the path itself, so they don't need access to the filesystem
+
+    native("path-join", Arity::AtLeast(1), "Joins parts of a path with the separator of the system", |ctx, args| {
+      let mut path = PathBuf::new();
+
+      for i in 0..args.len() {
+        path.push(ctx.arg::<String>(&args, i)?);
+      }
+
+      Ok(Value::String(path.to_string_lossy().into_owned()))
+    }),
+
+    native("basename", Arity::Exact(1), "The last part of a path, or nil if it has none", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      Ok(path_part(Path::new(&path).file_name()))
+    }),
+
+    native("dirname", Arity::Exact(1), "A path without its last part, or nil if it has none", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      Ok(path_part(Path::new(&path).parent().map(Path::as_os_str)))
+    }),
+
+    native("extension", Arity::Exact(1), "The extension of a path, without the dot, or nil if it has none", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      Ok(path_part(Path::new(&path).extension()))
+    }),
+
+    // -- Strings --
+
+    native("chars", Arity::Exact(1), "The characters of a string, as a list of strings", |ctx, args| {
+      match &args[0] {
+        Value::String(s) => Ok(Value::List(s.chars().map(|c| Value::String(c.to_string())).collect())),
+        other => ctx.error("type-error", &format!("Function 'chars' expected a string, got '{}'", other)),
+      }
+    }),
+
+    native("to-string", Arity::Exact(1), "A value as it would be printed", |_, args| {
+      Ok(Value::String(args[0].to_string()))
+    }),
+
+    native("parse-number", Arity::Exact(1), "The number written in a string, or nil if it isn't one", |ctx, args| {
+      let s: String = ctx.arg(&args, 0)?;
+      Ok(parse_number(&s).map(Value::Number).unwrap_or(Value::Nil))
+    }),
+
+    // -- Errors --
+
+    native("error?", Arity::Exact(1), "Whether a value is an error", |_, args| {
+      Ok(Value::Bool(matches!(args[0], Value::Error(_))))
+    }),
+
+    native("error-kind", Arity::Exact(1), "The kind of an error, such as 'type-error'", |ctx, args| {
+      Ok(Value::String(expect_error(&args[0], "error-kind", ctx)?.kind.clone()))
+    }),
+
+    native("error-message", Arity::Exact(1), "The message of an error", |ctx, args| {
+      Ok(Value::String(expect_error(&args[0], "error-message", ctx)?.message.clone()))
+    }),
+
+    native("error-data", Arity::Exact(1), "The value attached to an error", |ctx, args| {
+      Ok(expect_error(&args[0], "error-data", ctx)?.data.clone())
+    }),
+
+    native("error-position", Arity::Exact(1), "The [line column] where an error was raised", |ctx, args| {
+      let e = expect_error(&args[0], "error-position", ctx)?;
+      Ok(Value::Vector([Value::Number((e.pos.line + 1) as f64), Value::Number((e.pos.col + 1) as f64)].into_iter().collect()))
+    }),
+
+    native("error-trace", Arity::Exact(1), "The calls that led to an error, innermost first", |ctx, args| {
+      Ok(expect_error(&args[0], "error-trace", ctx)?.trace_value())
+    }),
+
+    // -- Foreign objects --
+
+    native("foreign?", Arity::Exact(1), "Whether a value is an object given by the host program", |_, args| {
+      Ok(Value::Bool(matches!(args[0], Value::Foreign(_))))
+    }),
+
+    native("foreign-type", Arity::Exact(1), "The type name of a foreign object", |ctx, args| {
+      match &args[0] {
+        Value::Foreign(o) => Ok(Value::String(o.type_name.clone())),
+        other => ctx.error("type-error", &format!("Function 'foreign-type' expected a foreign object, got '{}'", other)),
+      }
+    }),
+
+    native("methods", Arity::Exact(1), "The names of the methods of a foreign object, as a list", |ctx, args| {
+      match &args[0] {
+        Value::Foreign(o) => Ok(Value::List(o.method_names().into_iter().map(Value::String).collect())),
+        other => ctx.error("type-error", &format!("Function 'methods' expected a foreign object, got '{}'", other)),
+      }
+    }),
+  ])
+}
+
+// Reading the clock, with Capability::Time
+fn time_fns() -> Vec<(String, Value)> {
+  vec![
+    native("time_ms", Arity::Exact(0), "The milliseconds since the Unix epoch", |_, _| {
+      Ok(Value::Number(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64))
+    }),
+
+    native("time_sec", Arity::Exact(0), "The seconds since the Unix epoch", |_, _| {
+      Ok(Value::Number(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as f64))
+    }),
+  ]
+}
+
+// Running other programs, with Capability::Process; they get the environment variables as 'setenv' left them
+fn process_fns(vars: Rc<EnvVars>) -> Vec<(String, Value)> {
+  let (run_vars, spawn_vars, execute_vars, exit_code_vars) = (vars.clone(), vars.clone(), vars.clone(), vars);
+
+  vec![
+    native("process-run", Arity::Between(1, 3), "Runs a program with a list of arguments, returning its stdout, stderr and exit-code", move |ctx, args| {
+      process::run(ctx, args, &run_vars)
+    }),
+
+    native("process-spawn", Arity::Between(1, 3), "Starts a program with a list of arguments, returning a process to talk to it", move |ctx, args| {
+      process::spawn(ctx, args, &spawn_vars)
+    }),
+
+    native("execute", Arity::Exact(1), "Runs a shell command, returning what it printed", move |ctx, args| {
+      let command: String = ctx.arg(&args, 0)?;
+      let output = shell(command, &execute_vars).output().map_err(|e| ctx.raise("io-error", &format!("Couldn't run command: {}", e)))?;
+      Ok(Value::String(String::from_utf8_lossy(output.stdout.as_slice()).into_owned()))
+    }),
+
+    native("execute_exit_code", Arity::Exact(1), "Runs a shell command, returning its exit code", move |ctx, args| {
+      if let Value::String(s) = args[0].clone() {
+        let output = shell(s, &exit_code_vars).output().map_err(|e| ctx.raise("io-error", &format!("Couldn't run command: {}", e)))?;
   
-        Some(Value::Number(output.status.code()? as f64))
+        match output.status.code() {
+          Some(code) => Ok(Value::Number(code as f64)),
+          None => ctx.error("io-error", "The command was terminated by a signal"),
+        }
       }
       else {
-        None
+        ctx.error("type-error", &format!("Function 'execute_exit_code' expected a string, got '{}'", args[0]))
       }
-    } })),
+    }),
+  ]
+}
 
-    ("input".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
-      print!("{}", args[0]);
+// Reading and writing files, with Capability::Filesystem
+fn filesystem_fns() -> Vec<(String, Value)> {
+  vec![
+    native("cwd", Arity::Exact(0), "The working directory", |ctx, _| {
+      let dir = std::env::current_dir().map_err(|e| ctx.raise("io-error", &format!("Couldn't read the working directory: {}", e)))?;
+      Ok(Value::String(dir.to_string_lossy().into_owned()))
+    }),
 
-      let mut input = String::new();
+    // relative paths, in files and in 'process-run', are relative to the new directory afterwards
+    native("chdir", Arity::Exact(1), "Changes the working directory", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      std::env::set_current_dir(&path).map_err(|e| fs_error(ctx, "change to", &path, e))?;
 
-      std::io::stdout().flush().unwrap();
-      std::io::stdin().read_line(&mut input).unwrap();
+      Ok(Value::Nil)
+    }),
 
-      Some(Value::String(input))
-    } })),
+    native("read-file", Arity::Exact(1), "The contents of a file, as a string", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let contents = fs::read_to_string(&path).map_err(|e| fs_error(ctx, "read", &path, e))?;
 
-    /*
-    // Buggy. Will be uncommented when resolved.
-    ("input_num".into(), Value::Function(Function::NativeFn { arity: 1, call: |args| {
-      print!("{}", args[0]);
+      Ok(Value::String(contents))
+    }),
+
+    native("read-lines", Arity::Exact(1), "The lines of a file, as a list of strings", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let contents = fs::read_to_string(&path).map_err(|e| fs_error(ctx, "read", &path, e))?;
+
+      Ok(Value::List(contents.lines().map(|line| Value::String(line.to_owned())).collect()))
+    }),
+
+    native("write-file", Arity::Exact(2), "Writes a string to a file, replacing what it had", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let contents: String = ctx.arg(&args, 1)?;
+      fs::write(&path, contents).map_err(|e| fs_error(ctx, "write", &path, e))?;
+
+      Ok(Value::Nil)
+    }),
+
+    native("append-file", Arity::Exact(2), "Writes a string at the end of a file, creating it if it doesn't exist", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let contents: String = ctx.arg(&args, 1)?;
+
+      fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| fs_error(ctx, "write", &path, e))?;
+
+      Ok(Value::Nil)
+    }),
+
+    native("file-exists?", Arity::Exact(1), "Whether a file or directory exists", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      Ok(Value::Bool(Path::new(&path).exists()))
+    }),
+
+    // sorted, without '.' and '..'
+    native("list-dir", Arity::Exact(1), "The names of the entries of a directory, as a sorted list", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let mut names = Vec::new();
+
+      for entry in fs::read_dir(&path).map_err(|e| fs_error(ctx, "list", &path, e))? {
+        let entry = entry.map_err(|e| fs_error(ctx, "list", &path, e))?;
+        names.push(entry.file_name().to_string_lossy().into_owned());
+      }
+
+      names.sort();
+      Ok(Value::List(names.into_iter().map(Value::String).collect()))
+    }),
+
+    native("mkdir", Arity::Exact(1), "Creates a directory, along with any missing parent", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      fs::create_dir_all(&path).map_err(|e| fs_error(ctx, "create", &path, e))?;
+
+      Ok(Value::Nil)
+    }),
+
+    // directories must be empty, so a mistake can't delete a whole tree; a symlink is removed, not what it points to
+    native("remove", Arity::Exact(1), "Removes a file or an empty directory", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let metadata = fs::symlink_metadata(&path).map_err(|e| fs_error(ctx, "remove", &path, e))?;
+
+      let removed = if metadata.is_dir() { fs::remove_dir(&path) } else { fs::remove_file(&path) };
+      removed.map_err(|e| fs_error(ctx, "remove", &path, e))?;
+
+      Ok(Value::Nil)
+    }),
+
+    native("rename", Arity::Exact(2), "Moves a file or directory to another path", |ctx, args| {
+      let from: String = ctx.arg(&args, 0)?;
+      let to: String = ctx.arg(&args, 1)?;
+      fs::rename(&from, &to).map_err(|e| fs_error(ctx, "rename", &from, e))?;
 
+      Ok(Value::Nil)
+    }),
+
+    // {"type" "file"/"dir"/"symlink"/"other" "size" bytes "modified" seconds since the Unix epoch "readonly" bool}
+    native("file-stat", Arity::Exact(1), "The type, size, modification time and permissions of a file, as a map", |ctx, args| {
+      let path: String = ctx.arg(&args, 0)?;
+      let metadata = fs::symlink_metadata(&path).map_err(|e| fs_error(ctx, "read", &path, e))?;
+
+      let kind = match metadata.file_type() {
+        t if t.is_symlink() => "symlink",
+        t if t.is_dir() => "dir",
+        t if t.is_file() => "file",
+        _ => "other",
+      };
+
+      let modified = match metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(time) => Value::Number(time.as_secs_f64()),
+        None => Value::Nil,
+      };
+
+      Ok(Value::Map(persistent::Map::from_iter([
+        (Value::String("type".to_owned()), Value::String(kind.to_owned())),
+        (Value::String("size".to_owned()), Value::Number(metadata.len() as f64)),
+        (Value::String("modified".to_owned()), modified),
+        (Value::String("readonly".to_owned()), Value::Bool(metadata.permissions().readonly())),
+      ])))
+    }),
+  ]
+}
+
+// Reading and changing environment variables, with Capability::Env.
+// Changes are only seen by the interpreter and the programs it runs (see 'EnvVars').
+fn env_var_fns(vars: Rc<EnvVars>) -> Vec<(String, Value)> {
+  let (get_vars, set_vars) = (vars.clone(), vars.clone());
+
+  vec![
+    native("getenv", Arity::Exact(1), "The value of an environment variable, or nil if it isn't set", move |ctx, args| {
+      let name: String = ctx.arg(&args, 0)?;
+      Ok(get_vars.get(&name).map(Value::String).unwrap_or(Value::Nil))
+    }),
+
+    native("setenv", Arity::Exact(2), "Sets an environment variable, or unsets it if the value is nil", move |ctx, args| {
+      let name: String = ctx.arg(&args, 0)?;
+      let value: Option<String> = ctx.arg(&args, 1)?;
+
+      if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return ctx.error("value-error", &format!("Invalid environment variable name '{}'", name));
+      }
+
+      match value {
+        Some(value) if value.contains('\0') => { return ctx.error("value-error", "Environment variables can't contain null characters"); }
+        value => set_vars.set(name, value),
+      }
+
+      Ok(Value::Nil)
+    }),
+
+    // variables whose name or value isn't valid unicode are left out
+    native("env-vars", Arity::Exact(0), "Every environment variable, as a map from names to values", move |_, _| {
+      Ok(Value::Map(vars.all().into_iter().map(|(k, v)| (Value::String(k), Value::String(v))).collect()))
+    }),
+  ]
+}
+
+// Reading the standard input, with Capability::Stdin
+fn stdin_fns() -> Vec<(String, Value)> {
+  vec![
+    native("input", Arity::Exact(1), "Prints a prompt and reads a line from the standard input, or nil at its end", |ctx, args| {
+      prompt(ctx, &args[0])?;
+      Ok(read_line(ctx)?.map(Value::String).unwrap_or(Value::Nil))
+    }),
+
+    native("input_num", Arity::Exact(1), "Prints a prompt and reads a number from the standard input, or nil if it isn't one", |ctx, args| {
+      prompt(ctx, &args[0])?;
+      Ok(read_line(ctx)?.and_then(|line| parse_number(&line)).map(Value::Number).unwrap_or(Value::Nil))
+    }),
+
+    // without the new line at its end
+    native("read-line", Arity::Exact(0), "Reads a line from the standard input, or nil at its end", |ctx, _| {
+      Ok(read_line(ctx)?.map(Value::String).unwrap_or(Value::Nil))
+    }),
+
+    native("read-all", Arity::Exact(0), "Reads the rest of the standard input", |ctx, _| {
       let mut input = String::new();
+      std::io::stdin().read_to_string(&mut input).map_err(|e| ctx.raise("io-error", &format!("Couldn't read the standard input: {}", e)))?;
 
-      std::io::stdout().flush().unwrap();
-      std::io::stdin().read_line(&mut input).unwrap();
+      Ok(Value::String(input))
+    }),
 
-      match input.parse::<f64>() {
-        Ok(n) => Some(Value::Number(n)),
-        Err(_) => Some(Value::Nil)
+    // reads one line at a time, so it works on input of any size, and as it arrives
+    native("each-line", Arity::Exact(1), "Calls a function with every line of the standard input, until its end", |ctx, args| {
+      while let Some(line) = read_line(ctx)? {
+        ctx.call(args[0].clone(), vec![Value::String(line)])?;
       }
-    } })),
-    */
-  ])
+
+      Ok(Value::Nil)
+    }),
+  ]
+}
+
+// ---
+
+fn prompt(ctx: &Ctx, text: &Value) -> Result<(), Rc<Error>> {
+  print!("{}", text);
+  std::io::stdout().flush().map_err(|e| ctx.raise("io-error", &format!("Couldn't write to the standard output: {}", e)))
+}
+
+// A line of the standard input without its new line, or None at its end
+fn read_line(ctx: &Ctx) -> Result<Option<String>, Rc<Error>> {
+  let mut line = String::new();
+
+  let read = std::io::stdin().read_line(&mut line).map_err(|e| ctx.raise("io-error", &format!("Couldn't read the standard input: {}", e)))?;
+
+  if read == 0 {
+    return Ok(None);
+  }
+
+  if line.ends_with('\n') {
+    line.pop();
+
+    if line.ends_with('\r') {
+      line.pop();
+    }
+  }
+
+  Ok(Some(line))
+}
+
+// Surrounding whitespace is ignored; "inf" and "NaN" aren't numbers here
+fn parse_number(s: &str) -> Option<f64> {
+  s.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+fn native(name: &str, arity: Arity, doc: &str, call: impl Fn(&mut Ctx, Vec<Value>) -> Result<Value, Rc<Error>> + 'static) -> (String, Value) {
+  (name.to_owned(), Value::Function(Rc::new(Function::native(name, arity, doc, call))))
+}
+
+// A command running 'command' in the system's shell, with the interpreter's environment variables
+fn shell(command: String, vars: &EnvVars) -> Command {
+  let (program, flag) = if cfg!(target_os = "windows") { ("cmd", "/c") } else { ("sh", "-c") };
+
+  let mut shell = Command::new(program);
+  shell.arg(flag).arg(command);
+  vars.apply(&mut shell);
+  shell
+}
+
+fn fs_error(ctx: &Ctx, action: &str, path: &str, e: std::io::Error) -> Rc<Error> {
+  ctx.raise("io-error", &format!("Couldn't {} '{}': {}", action, path, e))
+}
+
+fn path_part(part: Option<&OsStr>) -> Value {
+  match part {
+    Some(part) => Value::String(part.to_string_lossy().into_owned()),
+    None => Value::Nil,
+  }
+}
+
+fn expect_map(value: &Value, function: &str, ctx: &Ctx) -> Result<persistent::Map<Value, Value>, Rc<Error>> {
+  match value {
+    Value::Map(m) => Ok(m.clone()),
+    other => ctx.error("type-error", &format!("Function '{}' expected a map, got '{}'", function, other))
+  }
+}
+
+fn expect_set(value: &Value, function: &str, ctx: &Ctx) -> Result<persistent::Set<Value>, Rc<Error>> {
+  match value {
+    Value::Set(s) => Ok(s.clone()),
+    other => ctx.error("type-error", &format!("Function '{}' expected a set, got '{}'", function, other))
+  }
+}
+
+fn expect_error(value: &Value, function: &str, ctx: &Ctx) -> Result<Rc<Error>, Rc<Error>> {
+  match value {
+    Value::Error(e) => Ok(e.clone()),
+    other => ctx.error("type-error", &format!("Function '{}' expected an error, got '{}'", function, other))
+  }
+}
+
+// Records are accessed through the names of their fields
+fn expect_field(record: &interpreter::Record, name: &Value, ctx: &Ctx) -> Result<usize, Rc<Error>> {
+  let index = match name {
+    Value::String(s) => record.field_index(s),
+    _ => None,
+  };
+
+  match index {
+    Some(i) => Ok(i),
+    None => ctx.error("value-error", &format!("Struct '{}' has no field '{}'", record.def.name, name))
+  }
+}
+
+fn expect_index(value: &Value, ctx: &Ctx) -> Result<usize, Rc<Error>> {
+  match value {
+    Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+    other => ctx.error("index-error", &format!("Expected a non-negative integer index, got '{}'", other))
+  }
+}
+
+// A map entry, written as a list or a vector with 2 elements
+fn expect_entry(value: &Value, ctx: &Ctx) -> Result<(Value, Value), Rc<Error>> {
+  let pair: Vec<&Value> = match value {
+    Value::List(l) => l.iter().collect(),
+    Value::Vector(v) => v.iter().collect(),
+    _ => vec![],
+  };
+
+  if pair.len() != 2 {
+    return ctx.error("type-error", &format!("Expected a map entry with a key and a value, got '{}'", value));
+  }
+
+  Ok((pair[0].clone(), pair[1].clone()))
+}
+
+fn elements(value: &Value, function: &str, ctx: &Ctx) -> Result<Vec<Value>, Rc<Error>> {
+  match value.elements() {
+    Some(items) => Ok(items),
+    None => ctx.error("type-error", &format!("Function '{}' expected a collection, got '{}'", function, value))
+  }
+}
+
+// Builds a collection of the same kind as 'like' out of 'items'
+fn collect_like(like: &Value, items: Vec<Value>, ctx: &Ctx) -> Result<Value, Rc<Error>> {
+  match like {
+    Value::Vector(_) => Ok(Value::Vector(items.into_iter().collect())),
+    Value::Set(_) => Ok(Value::Set(items.into_iter().collect())),
+    Value::Map(_) => {
+      let mut map = persistent::Map::new();
+
+      for item in items {
+        let (key, value) = expect_entry(&item, ctx)?;
+        map.insert_mut(key, value);
+      }
+
+      Ok(Value::Map(map))
+    }
+
+    _ => Ok(Value::List(items.into_iter().collect())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::{collect_cycles, Capabilities, Environment};
+  use crate::{interpreter::{self, Value}, lexer, parser};
+
+  thread_local! {
+    pub static LIVE_SCOPES: Cell<usize> = const { Cell::new(0) };
+  }
+
+  fn run(env: &mut Environment, source: &str) -> Value {
+    let exprs = lexer::Lexer::new(source).lex().and_then(|tokens| parser::Parser::new(tokens).parse()).unwrap();
+    interpreter::run(exprs, env).unwrap_or_else(|unwind| panic!("{}", interpreter::top_level_error(unwind)))
+  }
+
+  // How many scopes are left once the cycles are collected
+  fn live_scopes() -> usize {
+    collect_cycles();
+    LIVE_SCOPES.with(Cell::get)
+  }
+
+  #[test]
+  fn closures_stored_in_their_own_scope_are_freed() {
+    let mut env = Environment::without_prelude(Capabilities::NONE);
+
+    run(&mut env, "
+      (defn with-let (n) (let [f (fn (x) (+ x n))] (f 1)))
+      (defn with-defn (n) (do (defn inner (x) (+ x n)) (inner 1)))
+      (defn with-set (n) (do (set g (fn (x) (+ x n))) (g 1)))
+      (defn nested (n) (do (set g nil) (let [m 1] (set! g (fn (x) (+ x (+ n m))))) (g 1)))
+    ");
+
+    let before = live_scopes();
+    run(&mut env, "(dotimes [i 5000] (do (with-let i) (with-defn i) (with-set i) (nested i)))");
+
+    assert_eq!(live_scopes(), before);
+  }
+
+  #[test]
+  fn closures_still_referenced_are_kept() {
+    let mut env = Environment::without_prelude(Capabilities::NONE);
+
+    run(&mut env, "
+      (defn adder (n) (let [f (fn (x) (+ x n))] f))
+      (defn counter () (do (set n 0) (defn bump () (set! n (+ n 1))) bump))
+      (set add (adder 2))
+      (set bump (counter))
+      (set adders [(adder 10) (adder 20)])
+    ");
+
+    collect_cycles();
+    assert!(run(&mut env, "(add 1)") == Value::Number(3.0));
+    assert!(run(&mut env, "(do (bump) (bump))") == Value::Number(2.0));
+    assert!(run(&mut env, "(do (set second (get adders 1)) (second 1))") == Value::Number(21.0));
+  }
 }
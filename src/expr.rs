@@ -22,6 +22,10 @@ pub enum ExprData {
   Identifier(String),
   Bool(bool),
   List(Vec<Expr>, bool),
+  Map(Vec<(Expr, Expr)>),
+  Vector(Vec<Expr>),
+  Set(Vec<Expr>),
+  Record(String, Vec<(String, Expr)>),
   Keyword(String),
   Operator(String),
   Nil,
@@ -1,17 +1,481 @@
-use std::fmt::{Display, Formatter};
+use std::{any::Any, cell::{Cell, RefCell}, cmp::Ordering, collections::{hash_map::DefaultHasher, HashMap}, fmt::{Debug, Display, Formatter}, hash::{Hash, Hasher}, rc::Rc, time::{Duration, Instant}};
 
-use crate::{env, expr, util};
+use crate::{convert, env, expr, module, pattern, persistent, util};
 
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Function {
     UserFn {
-        params: Vec<String>,
+        // set by 'defn', for tracebacks
+        name: Option<String>,
+        params: Vec<pattern::Pattern>,
         body: expr::Expr,
         env: env::Environment,
     },
-    NativeFn {
-        arity: usize,
-        call: fn(Vec<Value>) -> Option<Value>,
+    NativeFn(NativeFn),
+    // functions generated by 'defstruct'
+    StructFn {
+        def: Rc<StructDef>,
+        kind: StructFnKind,
+    },
+}
+
+impl Function {
+    pub fn native(name: &str, arity: Arity, doc: &str, call: impl Fn(&mut Ctx, Vec<Value>) -> Result<Value, Rc<Error>> + 'static) -> Function {
+        Function::NativeFn(NativeFn { name: name.to_owned(), doc: doc.to_owned(), arity, call: Rc::new(call) })
+    }
+}
+
+// A function written in Rust. It can capture state, since it's a closure.
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: String,
+    pub doc: String,
+    pub arity: Arity,
+    #[allow(clippy::type_complexity)]
+    pub call: Rc<dyn Fn(&mut Ctx, Vec<Value>) -> Result<Value, Rc<Error>>>,
+}
+
+// How many arguments a native function takes
+#[derive(Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(min) => count >= min,
+            Arity::Between(min, max) => count >= min && count <= max,
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+// What a native function gets to work with, besides its arguments
+pub struct Ctx {
+    name: String,
+    pos: util::Position,
+}
+
+impl Ctx {
+    // The name of the native function being called
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Where the function was called from
+    pub fn pos(&self) -> &util::Position {
+        &self.pos
+    }
+
+    // Converts the argument at 'index' to a Rust type, raising a "type-error" naming the function if it can't be
+    pub fn arg<T: convert::FromValue>(&self, args: &[Value], index: usize) -> Result<T, Rc<Error>> {
+        let value = args.get(index).unwrap_or(&Value::Nil);
+
+        match T::from_value(value) {
+            Some(converted) => Ok(converted),
+            None => self.error("type-error", &format!("Function '{}' expected {} as argument {}, got '{}'", self.name, T::expected(), index + 1, value)),
+        }
+    }
+
+    // The Rust object inside the foreign value at 'index', if it holds a 'T'
+    pub fn foreign<'a, T: Any>(&self, args: &'a [Value], index: usize) -> Result<&'a T, Rc<Error>> {
+        let value = args.get(index).unwrap_or(&Value::Nil);
+
+        if let Some(object) = value.downcast_ref() {
+            return Ok(object);
+        }
+
+        self.error("type-error", &format!("Function '{}' expected a foreign '{}' as argument {}, got '{}'", self.name, std::any::type_name::<T>(), index + 1, value))
+    }
+
+    // Calls a Lispen function, e.g. one passed as an argument
+    pub fn call(&mut self, function: Value, args: Vec<Value>) -> Result<Value, Rc<Error>> {
+        call_function(function, args, self.pos.clone()).map_err(top_level_error)
+    }
+
+    // An error raised at the call of the native function
+    pub fn raise(&self, kind: &str, message: &str) -> Rc<Error> {
+        Error::new(kind.to_owned(), message.to_owned(), Value::Nil, self.pos.clone())
+    }
+
+    pub fn error<T>(&self, kind: &str, message: &str) -> Result<T, Rc<Error>> {
+        Err(self.raise(kind, message))
+    }
+
+    // Ends the script with an exit code, once the 'finally' blocks it's in have run
+    pub fn exit<T>(&self, code: i32) -> Result<T, Rc<Error>> {
+        Err(Error::exit(code, self.pos.clone()))
+    }
+}
+
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+pub enum StructFnKind {
+    Constructor,
+    Predicate,
+    Accessor(usize),
+}
+
+// An instance of a type declared with 'defstruct'; fields are stored in declaration order.
+#[derive(Clone)]
+pub struct Record {
+    pub def: Rc<StructDef>,
+    pub values: Rc<Vec<Value>>,
+}
+
+impl Record {
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.def.fields.iter().position(|f| f == name)
+    }
+
+    // Returns a copy of the record with one field replaced.
+    pub fn with(&self, index: usize, value: Value) -> Record {
+        let mut values = (*self.values).clone();
+        values[index] = value;
+
+        Record { def: self.def.clone(), values: Rc::new(values) }
+    }
+}
+
+// A Rust object handed to scripts as it is, such as a file handle or a connection.
+// Scripts can pass it around, print it and call its methods ('conn/query' calls the method "query" of 'conn'),
+// while native functions get the object back by downcasting it.
+pub struct Foreign {
+    pub type_name: String,
+    object: Rc<dyn Any>,
+    #[allow(clippy::type_complexity)]
+    display: Option<Rc<dyn Fn(&dyn Any) -> String>>,
+    methods: HashMap<String, Method>,
+}
+
+struct Method {
+    arity: Arity,
+    doc: String,
+    #[allow(clippy::type_complexity)]
+    call: Rc<dyn Fn(&mut Ctx, &dyn Any, Vec<Value>) -> Result<Value, Rc<Error>>>,
+}
+
+impl Foreign {
+    pub fn new<T: Any>(type_name: &str, object: T) -> Foreign {
+        Foreign { type_name: type_name.to_owned(), object: Rc::new(object), display: None, methods: HashMap::new() }
+    }
+
+    // How the object is printed; by default, as '<type_name>'
+    pub fn with_display<T: Any>(mut self, display: impl Fn(&T) -> String + 'static) -> Foreign {
+        self.display = Some(Rc::new(move |object| match object.downcast_ref::<T>() {
+            Some(object) => display(object),
+            None => "<foreign>".to_owned(),
+        }));
+
+        self
+    }
+
+    // Adds a method, which gets the object and the arguments it's called with
+    pub fn with_method<T: Any>(mut self, name: &str, arity: Arity, doc: &str, call: impl Fn(&mut Ctx, &T, Vec<Value>) -> Result<Value, Rc<Error>> + 'static) -> Foreign {
+        let type_name = self.type_name.clone();
+
+        let call = move |ctx: &mut Ctx, object: &dyn Any, args| match object.downcast_ref::<T>() {
+            Some(object) => call(ctx, object, args),
+            None => ctx.error("type-error", &format!("Method '{}' doesn't belong to a '{}'", ctx.name(), type_name)),
+        };
+
+        self.methods.insert(name.to_owned(), Method { arity, doc: doc.to_owned(), call: Rc::new(call) });
+        self
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.object.downcast_ref()
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::Foreign(Rc::new(self))
+    }
+
+    pub fn method_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.methods.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // The method 'name' bound to 'this', as a native function
+    pub fn bound_method(this: &Rc<Foreign>, name: &str) -> Option<Value> {
+        let method = this.methods.get(name)?;
+        let (call, object) = (method.call.clone(), this.object.clone());
+
+        let function = Function::native(&format!("{}/{}", this.type_name, name), method.arity, &method.doc, move |ctx, args| call(ctx, &*object, args));
+        Some(Value::Function(Rc::new(function)))
+    }
+}
+
+// A runtime error, raised by the interpreter or thrown with 'throw'.
+// 'kind' names the category of the error (e.g. "type-error"), and 'data' is any value attached to it.
+pub struct Error {
+    pub kind: String,
+    pub message: String,
+    pub data: Value,
+    pub pos: util::Position,
+    // the calls that were running when it was raised, outermost first
+    pub trace: Vec<Frame>,
+    // set when the script called 'exit': the error unwinds like any other, running 'finally' blocks,
+    // but 'catch' lets it through, so it reaches the host, which ends the program with this code
+    pub exit_code: Option<i32>,
+}
+
+impl Error {
+    pub fn new(kind: String, message: String, data: Value, pos: util::Position) -> Rc<Error> {
+        let trace = CALL_STACK.with(|stack| stack.borrow().clone());
+        Rc::new(Error { kind, message, data, pos, trace, exit_code: None })
+    }
+
+    pub fn exit(code: i32, pos: util::Position) -> Rc<Error> {
+        Rc::new(Error {
+            kind: "exit".to_owned(),
+            message: format!("Exited with code {}", code),
+            data: Value::Number(code as f64),
+            pos,
+            trace: Vec::new(),
+            exit_code: Some(code),
+        })
+    }
+
+    // The traceback as data: a vector of {"function" name "line" l "col" c} maps, innermost call first
+    pub fn trace_value(&self) -> Value {
+        Value::Vector(self.trace.iter().rev().map(|frame| {
+            Value::Map([
+                (Value::String("function".to_owned()), Value::String(frame.function.clone())),
+                (Value::String("line".to_owned()), Value::Number((frame.pos.line + 1) as f64)),
+                (Value::String("col".to_owned()), Value::Number((frame.pos.col + 1) as f64)),
+            ].into_iter().collect())
+        }).collect())
+    }
+}
+
+const MAX_TRACEBACK_LINES: usize = 20;
+
+// The message, followed by the traceback
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Error at {}:{} | {}", self.pos.line + 1, self.pos.col + 1, self.message)?;
+
+        let mut lines = Vec::new();
+        let mut frames = self.trace.iter().rev().peekable();
+
+        while let Some(frame) = frames.next() {
+            lines.push(format!("  in '{}', called at {}:{}", frame.function, frame.pos.line + 1, frame.pos.col + 1));
+
+            // deep recursion repeats the same frame many times
+            let mut repeated = 0;
+
+            while frames.next_if(|next| next.function == frame.function && next.pos.line == frame.pos.line && next.pos.col == frame.pos.col).is_some() {
+                repeated += 1;
+            }
+
+            if repeated > 0 {
+                lines.push(format!("  ... repeated {} more times", repeated));
+            }
+        }
+
+        for line in lines.iter().take(MAX_TRACEBACK_LINES) {
+            write!(f, "\n{}", line)?;
+        }
+
+        if lines.len() > MAX_TRACEBACK_LINES {
+            write!(f, "\n  ... and {} more", lines.len() - MAX_TRACEBACK_LINES)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// A function call in progress, and where it was called from.
+#[derive(Clone)]
+pub struct Frame {
+    pub function: String,
+    pub pos: util::Position,
+}
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// How much of the stack is kept free below the limit, for the work done between two calls:
+// a quarter of it, up to 8 MiB
+const MAX_STACK_MARGIN: usize = 8 << 20;
+
+thread_local! {
+    static CALL_STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static MAX_CALL_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_CALL_DEPTH) };
+    // the lowest address the stack can grow to before calls raise a "stack-overflow" error; 0 if unknown
+    static STACK_LIMIT: Cell<usize> = const { Cell::new(0) };
+    static LIMITS: Cell<Limits> = const { Cell::new(Limits { max_steps: None, max_size: None, timeout: None }) };
+    // the steps taken and the time the evaluation must end by, since the limits were last reset
+    static STEPS: Cell<u64> = const { Cell::new(0) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// How much an evaluation can use, for running untrusted scripts; None means unlimited.
+// Going past a limit raises a "limit-exceeded" error.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Limits {
+    // how many expressions can be evaluated
+    pub max_steps: Option<u64>,
+    // how many elements a collection, counting those of the collections nested in it, or characters a string can have
+    pub max_size: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+// How often, in steps, the clock is checked against the timeout
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+// Runs 'run' with its own limits, counting steps and time from zero, e.g. for each evaluation asked for by the host program.
+// The limits and counts in place before are put back afterwards, so an evaluation nested in another one doesn't use up its steps.
+pub fn with_limits<T>(limits: Limits, run: impl FnOnce() -> T) -> T {
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+
+    let previous = (LIMITS.with(|l| l.replace(limits)), STEPS.with(|s| s.replace(0)), DEADLINE.with(|d| d.replace(deadline)));
+    let result = run();
+
+    LIMITS.with(|l| l.set(previous.0));
+    STEPS.with(|s| s.set(previous.1));
+    DEADLINE.with(|d| d.set(previous.2));
+
+    result
+}
+
+// Counts the evaluation of one expression against the step limit and the timeout
+fn count_step(pos: &util::Position) -> Result<(), Unwind> {
+    let steps = STEPS.with(|steps| {
+        steps.set(steps.get() + 1);
+        steps.get()
+    });
+
+    if let Some(max) = LIMITS.with(Cell::get).max_steps {
+        if steps > max {
+            return error("limit-exceeded", &format!("Evaluation took more than {} steps", max), pos.clone());
+        }
+    }
+
+    if steps.is_multiple_of(CLOCK_CHECK_INTERVAL) {
+        if let Some(deadline) = DEADLINE.with(Cell::get) {
+            if Instant::now() > deadline {
+                let timeout = LIMITS.with(Cell::get).timeout.unwrap_or_default();
+                return error("limit-exceeded", &format!("Evaluation took longer than {:?}", timeout), pos.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Checks the size of a string or collection against the size limit.
+// Collections count the elements of the collections nested in them too, as many times as they appear,
+// so a value built by putting another one in itself over and over is refused before printing, comparing or hashing it
+// has to go through all of its copies.
+fn check_size(value: &Value, pos: &util::Position) -> Result<(), Unwind> {
+    let Some(max) = LIMITS.with(Cell::get).max_size else {
+        return Ok(());
+    };
+
+    if let Value::String(s) = value {
+        let size = s.chars().count();
+
+        if size > max {
+            return error("limit-exceeded", &format!("Value of size {} is bigger than the limit of {}", size, max), pos.clone());
+        }
+
+        return Ok(());
+    }
+
+    if nested_size(value, max) > max {
+        return error("limit-exceeded", &format!("Value with more than {} elements, counting those of the collections in it", max), pos.clone());
+    }
+
+    Ok(())
+}
+
+// How many elements a value holds, counting those of nested collections, records and error data;
+// stops once it's past 'max', so it never looks at more than 'max' elements.
+fn nested_size(value: &Value, max: usize) -> usize {
+    let mut size = 0;
+    let mut pending = vec![value];
+
+    while let Some(value) = pending.pop() {
+        let elements: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::List(l) => Box::new(l.iter()),
+            Value::Vector(v) => Box::new(v.iter()),
+            Value::Set(s) => Box::new(s.iter()),
+            Value::Map(m) => Box::new(m.iter().flat_map(|(k, v)| [k, v])),
+            Value::Record(r) => Box::new(r.values.iter()),
+            Value::Error(e) => Box::new(std::iter::once(&e.data)),
+            _ => continue,
+        };
+
+        for element in elements {
+            size += 1;
+
+            if size > max {
+                return size;
+            }
+
+            if matches!(element, Value::List(_) | Value::Vector(_) | Value::Set(_) | Value::Map(_) | Value::Record(_) | Value::Error(_)) {
+                pending.push(element);
+            }
+        }
+    }
+
+    size
+}
+
+pub fn set_max_call_depth(depth: usize) {
+    MAX_CALL_DEPTH.with(|max| max.set(depth));
+}
+
+// Tells the interpreter that the stack of the current thread has about 'size' bytes left below this point,
+// so deep recursion raises an error before overflowing it.
+pub fn set_stack_size(size: usize) {
+    let here = 0u8;
+    let start = &here as *const u8 as usize;
+
+    let margin = (size / 4).min(MAX_STACK_MARGIN);
+
+    STACK_LIMIT.with(|limit| limit.set(start.saturating_sub(size - margin)));
+}
+
+// Why another call would overflow the stack, if it would: going past the maximum depth, or getting too close to the end of the stack
+fn stack_exhausted(depth: usize) -> Option<String> {
+    let here = 0u8;
+    let address = &here as *const u8 as usize;
+
+    if depth >= MAX_CALL_DEPTH.with(Cell::get) {
+        Some(format!("too many nested calls ({})", depth))
+    } else if address < STACK_LIMIT.with(Cell::get) {
+        Some(format!("the thread ran out of stack after {} nested calls", depth))
+    } else {
+        None
     }
 }
 
@@ -20,100 +484,388 @@ pub enum Value {
     Number(f64),
     String(String),
     Bool(bool),
-    List(Vec<Value>),
-    Function(Function),
+    List(persistent::List<Value>),
+    Vector(persistent::Vector<Value>),
+    Map(persistent::Map<Value, Value>),
+    Set(persistent::Set<Value>),
+    Record(Record),
+    Function(Rc<Function>),
+    Error(Rc<Error>),
+    Foreign(Rc<Foreign>),
     Nil,
 }
 
 impl Value {
-    fn is_truthy(&self) -> bool {
-        match self.clone() {
-            Value::Number(n) => n != 0.0,
+    // Whether both values are the same object: collections and functions are compared by reference, everything else by value.
+    pub fn is_identical(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => a.ptr_eq(b),
+            (Value::Vector(a), Value::Vector(b)) => a.ptr_eq(b),
+            (Value::Map(a), Value::Map(b)) => a.ptr_eq(b),
+            (Value::Set(a), Value::Set(b)) => a.ptr_eq(b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Error(a), Value::Error(b)) => Rc::ptr_eq(a, b),
+            (Value::Foreign(a), Value::Foreign(b)) => Rc::ptr_eq(a, b),
+
+            _ => self == other
+        }
+    }
+
+    // The Rust object inside a foreign value, if it is one holding a 'T'
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        match self {
+            Value::Foreign(o) => o.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    // The elements of a collection in iteration order, or None if it isn't one.
+    // Sets and maps are iterated in sorted order, maps as [key value] vectors.
+    pub fn elements(&self) -> Option<Vec<Value>> {
+        match self {
+            Value::List(l) => Some(l.iter().cloned().collect()),
+            Value::Vector(v) => Some(v.iter().cloned().collect()),
+            Value::Set(s) => Some(s.sorted().into_iter().cloned().collect()),
+            Value::Map(m) => Some(m.sorted().into_iter().map(|(k, v)| Value::Vector([k.clone(), v.clone()].into_iter().collect())).collect()),
+            Value::Nil => Some(vec![]),
+
+            _ => None
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Number(_) => 0,
+            Value::String(_) => 1,
+            Value::Bool(_) => 2,
+            Value::List(_) => 3,
+            Value::Vector(_) => 4,
+            Value::Map(_) => 5,
+            Value::Set(_) => 6,
+            Value::Record(_) => 7,
+            Value::Function(_) => 8,
+            Value::Error(_) => 9,
+            Value::Foreign(_) => 10,
+            Value::Nil => 11,
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Number(n) => *n != 0.0,
             Value::String(s) => !s.is_empty(),
-            Value::Bool(b) => b,
+            Value::Bool(b) => *b,
             Value::List(l) => !l.is_empty(),
+            Value::Vector(v) => !v.is_empty(),
+            Value::Map(m) => !m.is_empty(),
+            Value::Set(s) => !s.is_empty(),
+            Value::Record(_) => true,
             Value::Function(_) => true,
+            Value::Error(_) => true,
+            Value::Foreign(_) => true,
             Value::Nil => false
         }
     }
 }
 
+// Values are compared structurally, collections element by element.
+// Functions and foreign objects are only equal to themselves, and different types are never equal;
+// when ordering, they are sorted by type in the order the variants are declared.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => a.len() == b.len() && a.iter().eq(b.iter()),
+            (Value::Vector(a), Value::Vector(b)) => a.len() == b.len() && a.iter().eq(b.iter()),
+            (Value::Map(a), Value::Map(b)) => a.len() == b.len() && a.iter().all(|(k, v)| b.get(k) == Some(v)),
+            (Value::Set(a), Value::Set(b)) => a.len() == b.len() && a.iter().all(|v| b.contains(v)),
+
+            _ => self.cmp(other) == Ordering::Equal
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => normalize(*a).total_cmp(&normalize(*b)),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::List(a), Value::List(b)) => a.iter().cmp(b.iter()),
+            (Value::Vector(a), Value::Vector(b)) => a.iter().cmp(b.iter()),
+            (Value::Map(a), Value::Map(b)) => a.sorted().cmp(&b.sorted()),
+            (Value::Set(a), Value::Set(b)) => a.sorted().cmp(&b.sorted()),
+            (Value::Record(a), Value::Record(b)) => a.def.name.cmp(&b.def.name).then_with(|| a.values.cmp(&b.values)),
+            (Value::Function(a), Value::Function(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Value::Error(a), Value::Error(b)) => (&a.kind, &a.message, &a.data).cmp(&(&b.kind, &b.message, &b.data)),
+            (Value::Foreign(a), Value::Foreign(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+
+        match self {
+            Value::Number(n) => normalize(*n).to_bits().hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::List(l) => l.iter().for_each(|v| v.hash(state)),
+            Value::Vector(v) => v.iter().for_each(|v| v.hash(state)),
+            // the iteration order of maps and sets depends on how they were built, so their entries are combined in an order independent way
+            Value::Map(m) => m.iter().map(|entry| hash_of(&entry)).fold(0u64, u64::wrapping_add).hash(state),
+            Value::Set(s) => s.iter().map(hash_of).fold(0u64, u64::wrapping_add).hash(state),
+            Value::Record(r) => {
+                r.def.name.hash(state);
+                r.values.hash(state);
+            }
+            Value::Function(f) => Rc::as_ptr(f).hash(state),
+            Value::Error(e) => (&e.kind, &e.message, &e.data).hash(state),
+            Value::Foreign(o) => Rc::as_ptr(o).hash(state),
+            Value::Nil => {}
+        }
+    }
+}
+
+// -0 and 0 are the same number
+fn normalize(n: f64) -> f64 {
+    if n == 0.0 { 0.0 } else { n }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             Value::Number(n) => write!(f, "{}", n),
             Value::String(s) => write!(f, "{}", s),
             Value::Bool(b) => write!(f, "{}", b),
-            Value::List(l) => {
-                write!(f, "(").unwrap();
-                
-                for (count, i) in l.iter().enumerate() {
-                    write!(f, "{}", i).unwrap();
-                    
-                    if count < l.len() - 1 {
-                        write!(f, " ").unwrap();
-                    }
-                }
-                
-                write!(f, ")").unwrap();
-                Ok(())
-            }
+            Value::List(l) => write_sequence(f, "(", l.iter(), ")"),
+            Value::Vector(v) => write_sequence(f, "[", v.iter(), "]"),
+            Value::Set(s) => write_sequence(f, "#{", s.sorted().into_iter(), "}"),
+            Value::Map(m) => write_sequence(f, "{", m.sorted().into_iter().map(|(k, v)| format!("{} {}", k, v)), "}"),
+            Value::Record(r) => write_sequence(
+                f,
+                &format!("#{}{{", r.def.name),
+                r.def.fields.iter().zip(r.values.iter()).map(|(k, v)| format!("{} {}", k, v)),
+                "}",
+            ),
+            Value::Error(e) => write!(f, "<{}: {}>", e.kind, e.message),
+            Value::Foreign(o) => match &o.display {
+                Some(display) => write!(f, "{}", display(&*o.object)),
+                None => write!(f, "<{}>", o.type_name),
+            },
             Value::Nil => write!(f, "nil"),
-            Value::Function(fun) => match fun {
-                Function::UserFn { params: _, body: _, env: _ } => write!(f, "<fn>"),
-                Function::NativeFn { arity: _, call: _ } => write!(f, "<native fn>"),
+            Value::Function(fun) => match &**fun {
+                Function::UserFn { .. } => write!(f, "<fn>"),
+                Function::NativeFn(_) => write!(f, "<native fn>"),
+                Function::StructFn { def: _, kind: _ } => write!(f, "<native fn>"),
             },
         }
     }
 }
 
-pub fn interpret(exprs: Vec<expr::Expr>, env: &mut env::Environment) {
-    if exprs.len() == 1 {
-        match execute(exprs[0].clone(), env) {
-            Some(res) => println!("< {}", res),
-            None => return,
-        }
-        
-        return;
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Display::fmt(self, f)
     }
-    
-    for expr in exprs {
-        if execute(expr, env).is_none() {
-            break;
+}
+
+fn write_sequence<T: Display>(f: &mut Formatter<'_>, open: &str, items: impl ExactSizeIterator<Item = T>, close: &str) -> Result<(), std::fmt::Error> {
+    write!(f, "{}", open)?;
+    let len = items.len();
+
+    for (count, i) in items.enumerate() {
+        write!(f, "{}", i)?;
+
+        if count < len - 1 {
+            write!(f, " ")?;
         }
     }
+
+    write!(f, "{}", close)
+}
+
+// Why the evaluation of an expression stopped without producing a value.
+// Besides errors, these carry 'break', 'continue' and 'return' up to the loop or function they leave.
+pub enum Unwind {
+    Error(Rc<Error>),
+    Break(Value, util::Position),
+    Continue(util::Position),
+    Return(Value, util::Position),
+    Recur(Vec<Value>, util::Position),
+    // a call in tail position, left for the caller to make so it doesn't grow the stack
+    TailCall(Box<TailCall>),
+}
+
+pub struct TailCall {
+    function: Value,
+    name: String,
+    args: Vec<Value>,
+    pos: util::Position,
+}
+
+pub fn raise(kind: &str, message: &str, pos: util::Position) -> Unwind {
+    Unwind::Error(Error::new(kind.to_owned(), message.to_owned(), Value::Nil, pos))
+}
+
+pub fn error<T>(kind: &str, message: &str, pos: util::Position) -> Result<T, Unwind> {
+    Err(raise(kind, message, pos))
+}
+
+// A "parse-error" for the mistakes the lexer or the parser found: the message and position are those of the first one,
+// and the data is every mistake, as a vector of {"message" m "line" l "col" c} maps.
+// 'source' names what was parsed, e.g. a file, and 'pos' is used when it isn't the code being run.
+pub fn parse_error(errors: &[util::SyntaxError], source: Option<&str>, pos: util::Position) -> Rc<Error> {
+    let data = Value::Vector(errors.iter().map(|e| {
+        Value::Map([
+            (Value::String("message".to_owned()), Value::String(e.message.clone())),
+            (Value::String("line".to_owned()), Value::Number((e.pos.line + 1) as f64)),
+            (Value::String("col".to_owned()), Value::Number((e.pos.col + 1) as f64)),
+        ].into_iter().collect())
+    }).collect());
+
+    let (message, first_pos) = match errors.first() {
+        Some(first) => (first.message.clone(), first.pos.clone()),
+        None => ("Unexpected end of input".to_owned(), util::Position::default()),
+    };
+
+    match source {
+        Some(source) => Error::new("parse-error".to_owned(), format!("Couldn't parse '{}': {} at {}:{}", source, message, first_pos.line + 1, first_pos.col + 1), data, pos),
+        None => Error::new("parse-error".to_owned(), message, data, first_pos),
+    }
+}
+
+// Turns 'break', 'continue' and 'return' that reached a function or the top level without being caught into errors.
+fn escaped(unwind: Unwind) -> Unwind {
+    let (message, pos) = match unwind {
+        Unwind::Break(_, pos) => ("'break' can only be used inside a loop", pos),
+        Unwind::Continue(pos) => ("'continue' can only be used inside a loop", pos),
+        Unwind::Return(_, pos) => ("'return' can only be used inside a function", pos),
+        Unwind::Recur(_, pos) => ("'recur' can only be used inside a 'loop'", pos),
+        Unwind::Error(e) => { return Unwind::Error(e); }
+        Unwind::TailCall(call) => { return Unwind::TailCall(call); }
+    };
+
+    raise("syntax-error", message, pos)
+}
+
+// What the code running in the current function (or at the top level) is inside of,
+// so 'try' can tell whether a 'break', 'continue', 'return' or 'recur' going through it will be handled.
+#[derive(Clone, Copy, Default)]
+struct Enclosing {
+    // loops that 'break' and 'continue' leave
+    loops: usize,
+    // 'loop' forms that 'recur' restarts
+    recur_loops: usize,
+    function: bool,
+}
+
+thread_local! {
+    static ENCLOSING: Cell<Enclosing> = const { Cell::new(Enclosing { loops: 0, recur_loops: 0, function: false }) };
+}
+
+fn within<T>(enclosing: Enclosing, run: impl FnOnce() -> T) -> T {
+    let previous = ENCLOSING.with(|e| e.replace(enclosing));
+    let result = run();
+    ENCLOSING.with(|e| e.set(previous));
+
+    result
+}
+
+// Like 'escaped', for a 'break', 'continue', 'return' or 'recur' that nothing around it handles, so it can be caught
+fn misplaced(unwind: Unwind) -> Unwind {
+    let enclosing = ENCLOSING.with(Cell::get);
+
+    match unwind {
+        Unwind::Break(..) | Unwind::Continue(_) if enclosing.loops == 0 => escaped(unwind),
+        Unwind::Return(..) if !enclosing.function => escaped(unwind),
+        Unwind::Recur(..) if enclosing.recur_loops == 0 => escaped(unwind),
+        unwind => unwind,
+    }
+}
+
+// The error an evaluation stopped with, once it reached the top level
+pub fn top_level_error(unwind: Unwind) -> Rc<Error> {
+    match escaped(unwind) {
+        Unwind::Error(e) => e,
+        _ => unreachable!("tail calls are always made before reaching the top level"),
+    }
+}
+
+pub fn report(unwind: Unwind) {
+    eprintln!("{}", top_level_error(unwind));
+}
+
+// Executes every expression in order, like the top level of a file, evaluating to the last one.
+pub fn run(exprs: Vec<expr::Expr>, env: &mut env::Environment) -> Result<Value, Unwind> {
+    within(Enclosing::default(), || {
+        let mut result = Value::Nil;
+
+        for expr in exprs {
+            result = execute(expr, env).map_err(escaped)?;
+        }
+
+        Ok(result)
+    })
 }
 
 // ---
 
-fn execute(expr: expr::Expr, env: &mut env::Environment) -> Option<Value> {
+fn execute(expr: expr::Expr, env: &mut env::Environment) -> Result<Value, Unwind> {
+    match execute_tail(expr, env) {
+        Err(Unwind::TailCall(t)) => call(t.function, t.name, t.args, t.pos),
+        result => result,
+    }
+}
+
+// Like 'execute', but a function call in 'expr' (or in the tail position of an 'if', 'do', 'let'...)
+// isn't made; it's returned as a 'TailCall' instead.
+fn execute_tail(expr: expr::Expr, env: &mut env::Environment) -> Result<Value, Unwind> {
+    count_step(&expr.pos)?;
+
     match expr.data {
-        expr::ExprData::Number(n) => Some(Value::Number(n)),
-        expr::ExprData::String(s) => Some(Value::String(s)),
+        expr::ExprData::Number(n) => Ok(Value::Number(n)),
+        expr::ExprData::String(s) => Ok(Value::String(s)),
         expr::ExprData::Identifier(i) => match env.get_variable(&i) {
-            Some(v) => Some(v),
+            Some(v) => Ok(v),
             None => {
-                util::print_error(
+                error(
+                    "name-error",
                     &format!("Variable '{}' doesn't exist in this scope", &i),
                     expr.pos,
                 )
             }
         },
         
-        expr::ExprData::Bool(b) => Some(Value::Bool(b)),
+        expr::ExprData::Bool(b) => Ok(Value::Bool(b)),
         
         // this is caught in parser, should not run
         expr::ExprData::Operator(_) => {
-            util::print_error("Operators cannot be used as values, only as instructions, by placing them as the first argument in lists", expr.pos)
+            error("syntax-error", "Operators cannot be used as values, only as instructions, by placing them as the first argument in lists", expr.pos)
         }
         
         expr::ExprData::Keyword(_) => {
-            util::print_error("Keywords cannot be used as values, only as instructions, by placing them as the first argument in lists", expr.pos)
+            error("syntax-error", "Keywords cannot be used as values, only as instructions, by placing them as the first argument in lists", expr.pos)
         }
         
         expr::ExprData::List(l, is_quote) => {
             if l.is_empty() {
-                return Some(Value::List(vec![]));
+                return Ok(Value::List(persistent::List::new()));
             }
             
             if is_quote {
@@ -122,13 +874,14 @@ fn execute(expr: expr::Expr, env: &mut env::Environment) -> Option<Value> {
                     vec.push(execute(expr, env)?);
                 }
                 
-                Some(Value::List(vec))
+                Ok(Value::List(vec.into_iter().collect()))
             } else {
                 match l[0].data.clone() {
                     expr::ExprData::Operator(o) => {
                         if l.len() != 3 {
                             // it's 2, but the operator also counts as an argument
-                            return util::print_error(
+                            return error(
+                                "arity-error",
                                 &format!(
                                     "Expected 2 arguments for the operator, got {}",
                                     l.len() - 1
@@ -143,237 +896,695 @@ fn execute(expr: expr::Expr, env: &mut env::Environment) -> Option<Value> {
                         match o.as_str() {
                             // for now, all operators will only support 2 arguments
                             "+" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Number(a + b)),
-                                (Value::String(a), Value::String(b)) => Some(Value::String(format!("{}{}", a, b))),
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                                (Value::String(a), Value::String(b)) => {
+                                    let joined = Value::String(format!("{}{}", a, b));
+                                    check_size(&joined, &expr.pos)?;
+
+                                    Ok(joined)
+                                }
                                 
-                                _ => util::print_error("Operator '+' can only be used with numbers and strings, both being of the same type", expr.pos)
+                                _ => error("type-error", "Operator '+' can only be used with numbers and strings, both being of the same type", expr.pos)
                             },
                             
                             "-" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Number(a - b)),
-                                _ => util::print_error("Operator '-' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+                                _ => error("type-error", "Operator '-' can only be used with numbers", expr.pos)
                             },
                             
                             "*" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Number(a * b)),
-                                _ => util::print_error("Operator '*' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
+                                _ => error("type-error", "Operator '*' can only be used with numbers", expr.pos)
                             },
                             
                             "/" => match (a, b) {
                                 (Value::Number(a), Value::Number(b)) => {
                                     if b == 0.0 {
-                                        return util::print_error("Cannot divide by zero", expr.pos);
+                                        return error("arithmetic-error", "Cannot divide by zero", expr.pos);
                                     }
                                     
-                                    Some(Value::Number(a / b))
+                                    Ok(Value::Number(a / b))
                                 }
                                 
-                                _ => util::print_error("Operator '-' can only be used with numbers", expr.pos)
+                                _ => error("type-error", "Operator '-' can only be used with numbers", expr.pos)
                             },
                             
                             ">" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a > b)),
-                                _ => util::print_error("Operator '>' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a > b)),
+                                _ => error("type-error", "Operator '>' can only be used with numbers", expr.pos)
                             },
                             
                             ">=" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a >= b)),
-                                _ => util::print_error("Operator '>=' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a >= b)),
+                                _ => error("type-error", "Operator '>=' can only be used with numbers", expr.pos)
                             },
                             
                             "<" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a < b)),
-                                _ => util::print_error("Operator '<' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a < b)),
+                                _ => error("type-error", "Operator '<' can only be used with numbers", expr.pos)
                             },
                             
                             "<=" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a <= b)),
-                                _ => util::print_error("Operator '<=' can only be used with numbers", expr.pos)
+                                (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a <= b)),
+                                _ => error("type-error", "Operator '<=' can only be used with numbers", expr.pos)
                             },
                             
-                            "=" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a == b)),
-                                (Value::String(a), Value::String(b)) => Some(Value::Bool(a == b)),
-                                _ => util::print_error("Operator '=' can only be used with numbers and strings, both being of the same type", expr.pos)
-                            },
-                            
-                            "!=" => match (a, b) {
-                                (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a != b)),
-                                (Value::String(a), Value::String(b)) => Some(Value::Bool(a != b)),
-                                _ => util::print_error("Operator '!=' can only be used with numbers and strings, both being of the same type", expr.pos)
-                            },
+                            "=" => Ok(Value::Bool(a == b)),
+                            "!=" => Ok(Value::Bool(a != b)),
                             
-                            op => util::print_error(&format!("Unknown operator : '{}'", op), expr.pos)
+                            op => error("syntax-error", &format!("Unknown operator : '{}'", op), expr.pos)
                         }
                     }
                     
                     expr::ExprData::Keyword(k) => match k.as_str() {
                         "set" => {
                             if l.len() != 3 {
-                                return util::print_error(&format!("Invalid number of arguments in 'set' expression; expected 3, got {}", l.len()), expr.pos);
+                                return error("arity-error", &format!("Invalid number of arguments in 'set' expression; expected 3, got {}", l.len()), expr.pos);
+                            }
+                            
+                            if let expr::ExprData::Identifier(name) = l[1].data.clone() {
+                                let value = execute(l[2].clone(), env)?;
+
+                                if matches!(&value, Value::Function(f) if matches!(**f, Function::NativeFn(_))) {
+                                    return error("value-error", &format!("Cannot reassign native function '{}'", name), expr.pos);
+                                }
+
+                                env.define_variable(name, value.clone());
+                                
+                                return Ok(value);
+                            }
+                            
+                            error("syntax-error", "Variable name must be an identifier", l[1].pos.clone())
+                        }
+                        
+                        // (defn name (args) (body))
+                        "defn" => {
+                            if l.len() != 4 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'defn' expression; expected 4, got {}", l.len()), expr.pos);
+                            }
+                            
+                            if let expr::ExprData::Identifier(name) = l[1].data.clone() {
+                                let params = parameters(&l[2])?;
+
+                                let f = Value::Function(Rc::new(Function::UserFn {
+                                    name: Some(name.clone()),
+                                    params,
+                                    body: l[3].clone(),
+                                    env: env.clone(),
+                                }));
+
+                                env.define_variable(name, f.clone());
+                                Ok(f)
+                            }
+                            else {
+                                error("syntax-error", "Function name must be an identifier", expr.pos)
+                            }
+                        }
+                        
+                        // (fn (args) (body))
+                        "fn" => {
+                            if l.len() != 3 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'fn' expression; expected 3, got {}", l.len()), expr.pos);
+                            }
+                            
+                            let params = parameters(&l[1])?;
+
+                            Ok(Value::Function(Rc::new(Function::UserFn {
+                                name: None,
+                                params,
+                                body: l[2].clone(),
+                                env: env.clone(),
+                            })))
+                        }
+
+                        // (let [pattern value ...] body...)
+                        "let" => {
+                            if l.len() < 2 {
+                                return error("arity-error", "Invalid number of arguments in 'let' expression; expected at least 2, got 1", expr.pos);
+                            }
+
+                            let bindings = match &l[1].data {
+                                expr::ExprData::Vector(items) | expr::ExprData::List(items, _) if items.len().is_multiple_of(2) => items,
+                                _ => { return error("syntax-error", "Invalid bindings in 'let' expression; expected a vector of patterns, each followed by a value", l[1].pos.clone()); }
+                            };
+
+                            let mut scope = env::Environment::from_enclosing(env.clone());
+
+                            for pair in bindings.chunks(2) {
+                                let pattern = pattern::Pattern::from_expr(&pair[0])?;
+                                let value = execute(pair[1].clone(), &mut scope)?;
+
+                                if !pattern.bind(&value, &mut scope) {
+                                    return error("match-error", &format!("Value '{}' doesn't match the binding pattern", value), pair[0].pos.clone());
+                                }
+                            }
+
+                            execute_tail_body(&l[2..], &mut scope)
+                        }
+                        
+                        // (if (condition) (then) (else?))
+                        "if" => {
+                            if l.len() < 3 || l.len() > 4 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'if' expression; expected 3 or 4, got {}", l.len()), expr.pos);
+                            }
+                            
+                            let condition = l[1].clone();
+                            let then_block = l[2].clone();
+                            let else_block = l.get(3).cloned();
+                            
+                            if execute(condition, env)?.is_truthy() {
+                                execute_tail(then_block, env)
+                            }
+                            else if let Some(expr) = else_block {
+                                execute_tail(expr, env)
+                            }
+                            else {
+                                Ok(Value::Nil)
+                            }
+                        }
+                        
+                        // (cond (condition body...) ... (else body...))
+                        "cond" => {
+                            for (i, clause) in l.iter().enumerate().skip(1) {
+                                let items = match &clause.data {
+                                    expr::ExprData::List(items, false) if !items.is_empty() => items,
+                                    _ => { return error("syntax-error", "Invalid clause in 'cond' expression; expected a list with a condition and a body", clause.pos.clone()); }
+                                };
+
+                                if is_keyword(&items[0], "else") {
+                                    if i != l.len() - 1 {
+                                        return error("syntax-error", "The 'else' clause must be the last one in a 'cond' expression", clause.pos.clone());
+                                    }
+
+                                    return execute_tail_body(&items[1..], env);
+                                }
+
+                                let condition = execute(items[0].clone(), env)?;
+
+                                if condition.is_truthy() {
+                                    // a clause without a body evaluates to its condition
+                                    if items.len() == 1 {
+                                        return Ok(condition);
+                                    }
+
+                                    return execute_tail_body(&items[1..], env);
+                                }
+                            }
+
+                            Ok(Value::Nil)
+                        }
+
+                        // (case value (datum body...) ((datum1 datum2) body...) ... (else body...))
+                        "case" => {
+                            if l.len() < 2 {
+                                return error("arity-error", "Invalid number of arguments in 'case' expression; expected at least 2, got 1", expr.pos);
+                            }
+
+                            let value = execute(l[1].clone(), env)?;
+
+                            for (i, clause) in l.iter().enumerate().skip(2) {
+                                let items = match &clause.data {
+                                    expr::ExprData::List(items, false) if !items.is_empty() => items,
+                                    _ => { return error("syntax-error", "Invalid clause in 'case' expression; expected a list with a datum and a body", clause.pos.clone()); }
+                                };
+
+                                if is_keyword(&items[0], "else") {
+                                    if i != l.len() - 1 {
+                                        return error("syntax-error", "The 'else' clause must be the last one in a 'case' expression", clause.pos.clone());
+                                    }
+
+                                    return execute_tail_body(&items[1..], env);
+                                }
+
+                                let datums = match &items[0].data {
+                                    expr::ExprData::List(datums, _) => datums.clone(),
+                                    _ => vec![items[0].clone()],
+                                };
+
+                                for datum in datums {
+                                    if !matches!(datum.data, expr::ExprData::Number(_) | expr::ExprData::String(_) | expr::ExprData::Bool(_) | expr::ExprData::Nil) {
+                                        return error("syntax-error", "Invalid datum in 'case' clause; expected a number, string, boolean or nil", datum.pos);
+                                    }
+
+                                    if execute(datum, env)? == value {
+                                        return execute_tail_body(&items[1..], env);
+                                    }
+                                }
+                            }
+
+                            Ok(Value::Nil)
+                        }
+
+                        // (when (condition) body...)
+                        // (unless (condition) body...)
+                        "when" | "unless" => {
+                            if l.len() < 2 {
+                                return error("arity-error", &format!("Invalid number of arguments in '{}' expression; expected at least 2, got 1", k), expr.pos);
+                            }
+
+                            if execute(l[1].clone(), env)?.is_truthy() == (k == "when") {
+                                execute_tail_body(&l[2..], env)
+                            }
+                            else {
+                                Ok(Value::Nil)
+                            }
+                        }
+
+                        // (do body...)
+                        "do" => execute_tail_body(&l[1..], env),
+
+                        // (match value (pattern body...) (pattern :when guard body...) ...)
+                        "match" => {
+                            if l.len() < 2 {
+                                return error("arity-error", "Invalid number of arguments in 'match' expression; expected at least 2, got 1", expr.pos);
+                            }
+
+                            let value = execute(l[1].clone(), env)?;
+
+                            for clause in l.iter().skip(2) {
+                                let items = match &clause.data {
+                                    expr::ExprData::List(items, false) if !items.is_empty() => items,
+                                    _ => { return error("syntax-error", "Invalid clause in 'match' expression; expected a list with a pattern and a body", clause.pos.clone()); }
+                                };
+
+                                let pattern = pattern::Pattern::from_expr(&items[0])?;
+
+                                let (guard, body) = match items.get(1).map(|e| &e.data) {
+                                    Some(expr::ExprData::Identifier(i)) if i == ":when" => match items.get(2) {
+                                        Some(guard) => (Some(guard), &items[3..]),
+                                        None => { return error("syntax-error", "Expected a guard expression after ':when'", items[1].pos.clone()); }
+                                    },
+                                    _ => (None, &items[1..]),
+                                };
+
+                                let mut scope = env::Environment::from_enclosing(env.clone());
+
+                                if !pattern.bind(&value, &mut scope) {
+                                    continue;
+                                }
+
+                                if let Some(guard) = guard {
+                                    if !execute(guard.clone(), &mut scope)?.is_truthy() {
+                                        continue;
+                                    }
+                                }
+
+                                return execute_tail_body(body, &mut scope);
+                            }
+
+                            error("match-error", &format!("No pattern matched value '{}'", value), expr.pos)
+                        }
+
+                        // (while (condition) (body))
+                        "while" => {
+                            if l.len() != 3 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'while' expression; expected 3, got {}", l.len()), expr.pos);
+                            }
+                            
+                            let condition = l[1].clone();
+                            
+                            while execute(condition.clone(), env)?.is_truthy() {
+                                if let Some(value) = execute_iteration(&l[2..], env)? {
+                                    return Ok(value);
+                                }
+                            }
+                            
+                            Ok(Value::Nil)
+                        }
+
+                        // (set! name value)
+                        "set!" => {
+                            if l.len() != 3 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'set!' expression; expected 3, got {}", l.len()), expr.pos);
+                            }
+
+                            let name = match &l[1].data {
+                                expr::ExprData::Identifier(name) => name.clone(),
+                                _ => { return error("syntax-error", "Variable name must be an identifier", l[1].pos.clone()); }
+                            };
+
+                            let value = execute(l[2].clone(), env)?;
+
+                            if !env.assign_variable(&name, value.clone()) {
+                                return error("name-error", &format!("Variable '{}' doesn't exist in this scope", name), expr.pos);
+                            }
+
+                            Ok(value)
+                        }
+
+                        // (dotimes [name count] body...)
+                        "dotimes" => {
+                            let spec = loop_spec(&l, 2, "dotimes", "a name and a count")?;
+                            let name = loop_name(&spec[0])?;
+                            let count = expect_number(execute(spec[1].clone(), env)?, "dotimes", spec[1].pos.clone())?;
+
+                            let mut i = 0.0;
+
+                            while i < count {
+                                let mut scope = env::Environment::from_enclosing(env.clone());
+                                scope.define_variable(name.clone(), Value::Number(i));
+
+                                if let Some(value) = execute_iteration(&l[2..], &mut scope)? {
+                                    return Ok(value);
+                                }
+
+                                i += 1.0;
+                            }
+
+                            Ok(Value::Nil)
+                        }
+
+                        // (for [name start end step?] body...)
+                        "for" => {
+                            let spec = loop_spec(&l, 3, "for", "a name, a start, an end and an optional step")?;
+
+                            if spec.len() > 4 {
+                                return error("syntax-error", "Invalid loop specification in 'for' expression; expected a name, a start, an end and an optional step", l[1].pos.clone());
+                            }
+
+                            let name = loop_name(&spec[0])?;
+                            let start = expect_number(execute(spec[1].clone(), env)?, "for", spec[1].pos.clone())?;
+                            let end = expect_number(execute(spec[2].clone(), env)?, "for", spec[2].pos.clone())?;
+
+                            let step = match spec.get(3) {
+                                Some(step) => expect_number(execute(step.clone(), env)?, "for", step.pos.clone())?,
+                                None => 1.0,
+                            };
+
+                            if step == 0.0 {
+                                return error("value-error", "The step of a 'for' loop cannot be zero", spec[3].pos.clone());
+                            }
+
+                            let mut i = start;
+
+                            // counts down when the step is negative; 'end' is never included
+                            while (step > 0.0 && i < end) || (step < 0.0 && i > end) {
+                                let mut scope = env::Environment::from_enclosing(env.clone());
+                                scope.define_variable(name.clone(), Value::Number(i));
+
+                                if let Some(value) = execute_iteration(&l[2..], &mut scope)? {
+                                    return Ok(value);
+                                }
+
+                                i += step;
+                            }
+
+                            Ok(Value::Nil)
+                        }
+
+                        // (for-each [pattern collection] body...)
+                        "for-each" | "doseq" => {
+                            let spec = loop_spec(&l, 2, &k, "a pattern and a collection")?;
+
+                            if spec.len() != 2 {
+                                return error("syntax-error", &format!("Invalid loop specification in '{}' expression; expected a pattern and a collection", k), l[1].pos.clone());
                             }
-                            
-                            if let expr::ExprData::Identifier(name) = l[1].data.clone() {
-                                let value = execute(l[2].clone(), env)?;
 
-                                if matches!(value, Value::Function(Function::NativeFn { arity: _, call: _ })) {
-                                    return util::print_error(&format!("Cannot reassign native function '{}'", name), expr.pos);
+                            let pattern = pattern::Pattern::from_expr(&spec[0])?;
+                            let collection = execute(spec[1].clone(), env)?;
+
+                            let items = match collection.elements() {
+                                Some(items) => items,
+                                None => { return error("type-error", &format!("Expected a collection to iterate over in '{}' expression, got '{}'", k, collection), spec[1].pos.clone()); }
+                            };
+
+                            for item in items {
+                                let mut scope = env::Environment::from_enclosing(env.clone());
+
+                                if !pattern.bind(&item, &mut scope) {
+                                    return error("match-error", &format!("Value '{}' doesn't match the loop pattern", item), spec[0].pos.clone());
                                 }
 
-                                env.define_variable(name, value.clone());
-                                
-                                return Some(value);
+                                if let Some(value) = execute_iteration(&l[2..], &mut scope)? {
+                                    return Ok(value);
+                                }
                             }
-                            
-                            None
+
+                            Ok(Value::Nil)
                         }
-                        
-                        // (defn name (args) (body))
-                        "defn" => {
-                            if l.len() != 4 {
-                                return util::print_error(&format!("Invalid number of arguments in 'defn' expression; expected 4, got {}", l.len()), expr.pos);
+
+                        // (loop [pattern value ...] body...), restarted by (recur value ...)
+                        "loop" => {
+                            if l.len() < 2 {
+                                return error("arity-error", "Invalid number of arguments in 'loop' expression; expected at least 2, got 1", expr.pos);
                             }
-                            
-                            if let expr::ExprData::Identifier(name) = l[1].data.clone() {
-                                let param_list = l[2].clone();
-                                let mut string_list: Vec<String> = Vec::new();
-                                
-                                if let expr::ExprData::List(list, _) = param_list.data {
-                                    for param in list {
-                                        if let expr::ExprData::Identifier(name) = param.data.clone() {
-                                            string_list.push(name);
-                                        } else {
-                                            return util::print_error(&format!("Invalid argument in function '{}'; expected an identifier", name), expr.pos);
+
+                            let bindings = match &l[1].data {
+                                expr::ExprData::Vector(items) | expr::ExprData::List(items, _) if items.len().is_multiple_of(2) => items,
+                                _ => { return error("syntax-error", "Invalid bindings in 'loop' expression; expected a vector of patterns, each followed by a value", l[1].pos.clone()); }
+                            };
+
+                            let mut patterns = Vec::new();
+                            let mut values = Vec::new();
+
+                            for pair in bindings.chunks(2) {
+                                patterns.push(pattern::Pattern::from_expr(&pair[0])?);
+                                values.push(execute(pair[1].clone(), env)?);
+                            }
+
+                            loop {
+                                let mut scope = env::Environment::from_enclosing(env.clone());
+
+                                for (pattern, value) in patterns.iter().zip(values) {
+                                    if !pattern.bind(&value, &mut scope) {
+                                        return error("match-error", &format!("Value '{}' doesn't match the binding pattern", value), l[1].pos.clone());
+                                    }
+                                }
+
+                                let enclosing = ENCLOSING.with(Cell::get);
+
+                                match within(Enclosing { recur_loops: enclosing.recur_loops + 1, ..enclosing }, || execute_body(&l[2..], &mut scope)) {
+                                    Err(Unwind::Recur(new_values, pos)) => {
+                                        if new_values.len() != patterns.len() {
+                                            return error("arity-error", &format!("Invalid number of arguments in 'recur' expression; expected {}, got {}", patterns.len(), new_values.len()), pos);
                                         }
+
+                                        values = new_values;
                                     }
-                                    
-                                    let f = Value::Function(Function::UserFn {
-                                        params: string_list,
-                                        body: l[3].clone(),
-                                        env: env.clone(),
-                                    });
-                                    
-                                    env.define_variable(name, f.clone());
-                                    Some(f)
-                                } else {
-                                    util::print_error(&format!("Invalid argument for the declaration of the function '{}'; expected a list of identifiers for the parameter list", name), expr.pos)
+
+                                    result => return result,
                                 }
                             }
-                            else {
-                                util::print_error("Function name must be an identifier", expr.pos)
+                        }
+
+                        // (recur value ...)
+                        "recur" => {
+                            let mut values = Vec::new();
+
+                            for arg in l.iter().skip(1) {
+                                values.push(execute(arg.clone(), env)?);
                             }
+
+                            Err(Unwind::Recur(values, expr.pos))
                         }
-                        
-                        // (fn (args) (body))
-                        "fn" => {
-                            if l.len() != 3 {
-                                return util::print_error(&format!("Invalid number of arguments in 'fn' expression; expected 3, got {}", l.len()), expr.pos);
+
+                        // (throw error) or (throw kind message data?)
+                        "throw" | "raise" => {
+                            if l.len() < 2 || l.len() > 4 {
+                                return error("arity-error", &format!("Invalid number of arguments in '{}' expression; expected 2 to 4, got {}", k, l.len()), expr.pos);
                             }
-                            
-                            let param_list = l[1].clone();
-                            let mut string_list: Vec<String> = Vec::new();
-                            
-                            if let expr::ExprData::List(list, _) = param_list.data {
-                                for param in list {
-                                    if let expr::ExprData::Identifier(name) = param.data.clone() {
-                                        string_list.push(name);
-                                    } else {
-                                        return util::print_error("Invalid argument in function expression; expected an identifier", expr.pos);
+
+                            let mut args = Vec::new();
+
+                            for arg in l.iter().skip(1) {
+                                args.push(execute(arg.clone(), env)?);
+                            }
+
+                            Err(Unwind::Error(thrown(args, expr.pos)?))
+                        }
+
+                        // (try body... (catch pattern handler...) (finally cleanup...))
+                        "try" => {
+                            let mut body = &l[1..];
+                            let mut finally = None;
+                            let mut catch = None;
+
+                            if let Some((last, rest)) = body.split_last() {
+                                if let Some(items) = clause(last, "finally") {
+                                    finally = Some(&items[1..]);
+                                    body = rest;
+                                }
+                            }
+
+                            if let Some((last, rest)) = body.split_last() {
+                                if let Some(items) = clause(last, "catch") {
+                                    if items.len() < 2 {
+                                        return error("syntax-error", "Invalid 'catch' clause; expected a pattern for the error, followed by a body", last.pos.clone());
                                     }
+
+                                    catch = Some((pattern::Pattern::from_expr(&items[1])?, &items[2..]));
+                                    body = rest;
                                 }
-                                
-                                Some(Value::Function(Function::UserFn {
-                                    params: string_list,
-                                    body: l[2].clone(),
-                                    env: env.clone(),
-                                }))
-                            } else {
-                                util::print_error("Invalid argument for the definition of a function; expected a list of identifiers for the parameter list", expr.pos)
                             }
+
+                            if let Some(misplaced) = body.iter().find(|e| clause(e, "catch").is_some() || clause(e, "finally").is_some()) {
+                                return error("syntax-error", "A 'try' expression can only have one 'catch' clause, followed by one 'finally' clause, at its end", misplaced.pos.clone());
+                            }
+
+                            // a misplaced 'break' or 'return' is an error, caught like the others
+                            let mut result = execute_body(body, env).map_err(misplaced);
+
+                            // 'exit' isn't caught, so the script ends however deep it was called
+                            match (&result, &catch) {
+                                (Err(Unwind::Error(e)), Some((pattern, handler))) if e.exit_code.is_none() => {
+                                    let mut scope = env::Environment::from_enclosing(env.clone());
+                                    let value = Value::Error(e.clone());
+
+                                    result = if pattern.bind(&value, &mut scope) {
+                                        execute_body(handler, &mut scope)
+                                    } else {
+                                        Err(Unwind::Error(e.clone()))
+                                    };
+                                }
+
+                                _ => {}
+                            }
+
+                            // the cleanup runs however the body was left, but an error in it takes precedence
+                            if let Some(cleanup) = finally {
+                                execute_body(cleanup, env)?;
+                            }
+
+                            result
                         }
-                        
-                        // (if (condition) (then) (else?))
-                        "if" => {
-                            if l.len() < 3 || l.len() > 4 {
-                                return util::print_error(&format!("Invalid number of arguments in 'if' expression; expected 3 or 4, got {}", l.len()), expr.pos);
+
+                        // (import "path" :as name?)
+                        // (require name :as name?)
+                        "import" | "require" => {
+                            let path = match (k.as_str(), l.get(1).map(|e| &e.data)) {
+                                ("import", Some(expr::ExprData::String(path))) => path.clone(),
+                                ("require", Some(expr::ExprData::Identifier(name))) => name.clone(),
+
+                                ("import", _) => { return error("syntax-error", "Expected the path of a module after 'import'", expr.pos); }
+                                _ => { return error("syntax-error", "Expected the name of a module after 'require'", expr.pos); }
+                            };
+
+                            let name = match &l[2..] {
+                                [] => module::default_name(&path),
+                                [as_keyword, alias] if matches!(&as_keyword.data, expr::ExprData::Identifier(a) if a == ":as") => match &alias.data {
+                                    expr::ExprData::Identifier(alias) => alias.clone(),
+                                    _ => { return error("syntax-error", "Expected a name after ':as'", alias.pos.clone()); }
+                                },
+                                _ => { return error("syntax-error", &format!("Invalid arguments in '{}' expression; expected a module, optionally followed by ':as' and a name", k), expr.pos); }
+                            };
+
+                            let exports = module::import(&path, env, expr.pos)?;
+                            env.define_variable(name, exports.clone());
+
+                            Ok(exports)
+                        }
+
+                        // (export name...)
+                        "export" => {
+                            let mut names = Vec::new();
+
+                            for item in l.iter().skip(1) {
+                                match &item.data {
+                                    expr::ExprData::Identifier(name) => names.push(name.clone()),
+                                    _ => { return error("syntax-error", "Expected the name of a variable to export", item.pos.clone()); }
+                                }
                             }
-                            
-                            let condition = l[1].clone();
-                            let then_block = l[2].clone();
-                            let else_block = l.get(3).cloned();
-                            
-                            if execute(condition, env)?.is_truthy() {
-                                execute(then_block, env)
+
+                            module::export(names, env, expr.pos)?;
+                            Ok(Value::Nil)
+                        }
+
+                        // (break value?)
+                        "break" => {
+                            if l.len() > 2 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'break' expression; expected 1 or 2, got {}", l.len()), expr.pos);
                             }
-                            else if let Some(expr) = else_block {
-                                execute(expr, env)
+
+                            let value = match l.get(1) {
+                                Some(e) => execute(e.clone(), env)?,
+                                None => Value::Nil,
+                            };
+
+                            Err(Unwind::Break(value, expr.pos))
+                        }
+
+                        // (continue)
+                        "continue" => {
+                            if l.len() != 1 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'continue' expression; expected 1, got {}", l.len()), expr.pos);
                             }
-                            else {
-                                Some(Value::Nil)
+
+                            Err(Unwind::Continue(expr.pos))
+                        }
+
+                        // (return value?)
+                        "return" => {
+                            if l.len() > 2 {
+                                return error("arity-error", &format!("Invalid number of arguments in 'return' expression; expected 1 or 2, got {}", l.len()), expr.pos);
                             }
+
+                            let value = match l.get(1) {
+                                Some(e) => execute(e.clone(), env)?,
+                                None => Value::Nil,
+                            };
+
+                            Err(Unwind::Return(value, expr.pos))
                         }
                         
-                        // (while (condition) (body))
-                        "while" => {
-                            if l.len() != 3 {
-                                return util::print_error(&format!("Invalid number of arguments in 'while' expression; expected 3, got {}", l.len()), expr.pos);
+                        // (defstruct name field1 field2 ...)
+                        "defstruct" => {
+                            if l.len() < 2 {
+                                return error("arity-error", "Invalid number of arguments in 'defstruct' expression; expected at least 2, got 1", expr.pos);
                             }
-                            
-                            let condition = l[1].clone();
-                            let body = l[2].clone();
-                            
-                            while execute(condition.clone(), env)?.is_truthy() {
-                                execute(body.clone(), env);
+
+                            let name = match l[1].data.clone() {
+                                expr::ExprData::Identifier(name) => name,
+                                _ => { return error("syntax-error", "Struct name must be an identifier", expr.pos); }
+                            };
+
+                            let mut fields: Vec<String> = Vec::new();
+
+                            for field in l.iter().skip(2) {
+                                match field.data.clone() {
+                                    expr::ExprData::Identifier(f) if fields.contains(&f) => {
+                                        return error("syntax-error", &format!("Duplicate field '{}' in struct '{}'", f, name), field.pos.clone());
+                                    }
+                                    expr::ExprData::Identifier(f) => fields.push(f),
+                                    _ => { return error("syntax-error", &format!("Invalid field in struct '{}'; expected an identifier", name), field.pos.clone()); }
+                                }
                             }
-                            
-                            Some(Value::Nil)
+
+                            let def = Rc::new(StructDef { name: name.clone(), fields });
+                            let struct_fn = |kind| Value::Function(Rc::new(Function::StructFn { def: def.clone(), kind }));
+
+                            env.define_variable(format!("make-{}", name), struct_fn(StructFnKind::Constructor));
+                            env.define_variable(format!("{}?", name), struct_fn(StructFnKind::Predicate));
+
+                            for (i, field) in def.fields.iter().enumerate() {
+                                env.define_variable(format!("{}-{}", name, field), struct_fn(StructFnKind::Accessor(i)));
+                            }
+
+                            Ok(Value::Nil)
                         }
                         
-                        kw => util::print_error(&format!("Keyword '{}' cannot be used as instruction", kw), expr.pos)
+                        kw => error("syntax-error", &format!("Keyword '{}' cannot be used as instruction", kw), expr.pos)
                     },
                     
                     expr::ExprData::Identifier(name) => {
                         let function = match env.get_variable(&name) {
                             Some(f) => f,
-                            None => { return util::print_error(&format!("Variable '{}' doesn't exist in this scope", &name), expr.pos); }
+                            None => { return error("name-error", &format!("Variable '{}' doesn't exist in this scope", &name), expr.pos); }
                         };
                         
-                        if let Value::Function(fun) = function {
-                            if let Function::UserFn { params, body, env: fn_env } = fun {
-                                let mut new_env = env::Environment::from_enclosing(fn_env);
-                                
-                                if l.len() - 1 != params.len() {
-                                    return util::print_error(&format!("Invalid number of arguments; expected {}, got {}", params.len(), l.len() - 1), expr.pos);
-                                }
-                                
-                                for (i, param) in params.iter().enumerate() {
-                                    let arg = execute(l[i + 1].clone(), env)?;
-                                    new_env.define_variable(param.into(), arg.clone());
-                                }
-                                
-                                return execute(body, &mut new_env);
-                            }
-                            
-                            
-                            if let Function::NativeFn { arity, call } = fun {
-                                if l.len() - 1 != arity {
-                                    return util::print_error(&format!("Invalid number of arguments; expected {}, got {}", arity, l.len() - 1), expr.pos);
-                                }
-
-                                let mut args: Vec<Value> = Vec::new();
+                        if !matches!(function, Value::Function(_)) {
+                            return error("type-error", &format!("Value '{}' isn't a function", &name), expr.pos);
+                        }
 
-                                for arg in l.iter().skip(1).cloned() {
-                                    args.push(execute(arg, env)?);
-                                }
+                        let mut args: Vec<Value> = Vec::new();
 
-                                return call(args);
-                            }
+                        for arg in l.iter().skip(1).cloned() {
+                            args.push(execute(arg, env)?);
                         }
-                        
-                        util::print_error(&format!("Value '{}' isn't a function", &name), expr.pos)
+
+                        Err(Unwind::TailCall(Box::new(TailCall { function, name, args, pos: expr.pos })))
                     }
                     
-                    expr::ExprData::Nil => Some(Value::Nil),
+                    expr::ExprData::Nil => Ok(Value::Nil),
                     
                     _ => {
                         let mut vec = vec![];
@@ -381,12 +1592,325 @@ fn execute(expr: expr::Expr, env: &mut env::Environment) -> Option<Value> {
                             vec.push(execute(expr, env)?);
                         }
                         
-                        Some(Value::List(vec))
+                        let list = Value::List(vec.into_iter().collect());
+                        check_size(&list, &expr.pos)?;
+
+                        Ok(list)
                     }
                 }
             }
         }
         
-        expr::ExprData::Nil => Some(Value::Nil),
+        expr::ExprData::Map(pairs) => {
+            let mut map = persistent::Map::new();
+
+            for (key_expr, value_expr) in pairs {
+                let key = execute(key_expr, env)?;
+                map.insert_mut(key, execute(value_expr, env)?);
+            }
+
+            let map = Value::Map(map);
+            check_size(&map, &expr.pos)?;
+
+            Ok(map)
+        }
+
+        expr::ExprData::Vector(items) => {
+            let mut vec = vec![];
+
+            for item in items {
+                vec.push(execute(item, env)?);
+            }
+
+            let vec = Value::Vector(vec.into_iter().collect());
+            check_size(&vec, &expr.pos)?;
+
+            Ok(vec)
+        }
+
+        expr::ExprData::Set(items) => {
+            let mut set = persistent::Set::new();
+
+            for item in items {
+                set.insert_mut(execute(item, env)?);
+            }
+
+            let set = Value::Set(set);
+            check_size(&set, &expr.pos)?;
+
+            Ok(set)
+        }
+
+        expr::ExprData::Record(name, fields) => {
+            let def = match env.get_variable(&format!("make-{}", name)) {
+                Some(Value::Function(f)) => match &*f {
+                    Function::StructFn { def, kind: StructFnKind::Constructor } => def.clone(),
+                    _ => { return error("name-error", &format!("Unknown struct '{}'", name), expr.pos); }
+                },
+                _ => { return error("name-error", &format!("Unknown struct '{}'", name), expr.pos); }
+            };
+
+            let mut values: Vec<Option<Value>> = vec![None; def.fields.len()];
+
+            for (field, value_expr) in fields {
+                match def.fields.iter().position(|f| *f == field) {
+                    Some(i) => values[i] = Some(execute(value_expr, env)?),
+                    None => { return error("value-error", &format!("Struct '{}' has no field '{}'", name, field), expr.pos); }
+                }
+            }
+
+            let mut record = Vec::new();
+
+            for (field, value) in def.fields.iter().zip(values) {
+                match value {
+                    Some(v) => record.push(v),
+                    None => { return error("value-error", &format!("Missing field '{}' in '{}' record", field, name), expr.pos); }
+                }
+            }
+
+            let record = Value::Record(Record { def, values: Rc::new(record) });
+            check_size(&record, &expr.pos)?;
+
+            Ok(record)
+        }
+
+        expr::ExprData::Nil => Ok(Value::Nil),
+    }
+}
+
+// Executes every expression in order, evaluating to the last one (or nil, if there's none).
+fn execute_body(body: &[expr::Expr], env: &mut env::Environment) -> Result<Value, Unwind> {
+    match execute_tail_body(body, env) {
+        Err(Unwind::TailCall(t)) => call(t.function, t.name, t.args, t.pos),
+        result => result,
+    }
+}
+
+// Like 'execute_body', with the last expression in tail position.
+fn execute_tail_body(body: &[expr::Expr], env: &mut env::Environment) -> Result<Value, Unwind> {
+    let Some((last, rest)) = body.split_last() else {
+        return Ok(Value::Nil);
+    };
+
+    for expr in rest {
+        execute(expr.clone(), env)?;
+    }
+
+    execute_tail(last.clone(), env)
+}
+
+// Every parameter of a function is a pattern; a list of plain names is the common case.
+fn parameters(list: &expr::Expr) -> Result<Vec<pattern::Pattern>, Unwind> {
+    let params = match &list.data {
+        expr::ExprData::List(params, _) => params,
+        _ => { return error("syntax-error", "Invalid parameter list for function; expected a list of parameters", list.pos.clone()); }
+    };
+
+    let mut patterns = Vec::new();
+
+    for param in params {
+        patterns.push(pattern::Pattern::from_expr(param)?);
+    }
+
+    Ok(patterns)
+}
+
+// Runs one iteration of a loop's body, returning the value to leave the loop with if it hit a 'break'.
+fn execute_iteration(body: &[expr::Expr], env: &mut env::Environment) -> Result<Option<Value>, Unwind> {
+    let enclosing = ENCLOSING.with(Cell::get);
+
+    match within(Enclosing { loops: enclosing.loops + 1, ..enclosing }, || execute_body(body, env)) {
+        Ok(_) | Err(Unwind::Continue(_)) => Ok(None),
+        Err(Unwind::Break(value, _)) => Ok(Some(value)),
+        Err(unwind) => Err(unwind),
+    }
+}
+
+// The bracketed specification right after a loop keyword, e.g. '[i 10]' in '(dotimes [i 10] ...)'
+fn loop_spec(l: &[expr::Expr], min: usize, form: &str, expected: &str) -> Result<Vec<expr::Expr>, Unwind> {
+    let pos = l[0].pos.clone();
+
+    match l.get(1).map(|e| &e.data) {
+        Some(expr::ExprData::Vector(items) | expr::ExprData::List(items, _)) if items.len() >= min => Ok(items.clone()),
+        _ => error("syntax-error", &format!("Invalid loop specification in '{}' expression; expected {}", form, expected), pos),
+    }
+}
+
+fn loop_name(expr: &expr::Expr) -> Result<String, Unwind> {
+    match &expr.data {
+        expr::ExprData::Identifier(name) => Ok(name.clone()),
+        _ => error("syntax-error", "Loop variable must be an identifier", expr.pos.clone()),
+    }
+}
+
+fn expect_number(value: Value, form: &str, pos: util::Position) -> Result<f64, Unwind> {
+    match value {
+        Value::Number(n) => Ok(n),
+        other => error("type-error", &format!("Expected a number in '{}' expression, got '{}'", form, other), pos),
+    }
+}
+
+// The items of a '(keyword ...)' clause, such as '(catch e ...)' in a 'try' expression
+fn clause<'a>(expr: &'a expr::Expr, keyword: &str) -> Option<&'a [expr::Expr]> {
+    match &expr.data {
+        expr::ExprData::List(items, false) if items.first().is_some_and(|first| is_keyword(first, keyword)) => Some(items),
+        _ => None,
+    }
+}
+
+// The error raised by 'throw': an error value is rethrown as it is, a string becomes the message
+// of a generic error, and any other value is attached as its data. With more arguments, they are the kind, message and data.
+fn thrown(args: Vec<Value>, pos: util::Position) -> Result<Rc<Error>, Unwind> {
+    let mut args = args.into_iter();
+    let first = args.next().unwrap_or(Value::Nil);
+
+    let message = match args.next() {
+        Some(Value::String(message)) => message,
+        Some(other) => { return error("type-error", &format!("Expected an error message, got '{}'", other), pos); }
+
+        None => return Ok(match first {
+            Value::Error(e) => e,
+            Value::String(message) => Error::new("error".to_owned(), message, Value::Nil, pos),
+            other => Error::new("error".to_owned(), other.to_string(), other, pos),
+        }),
+    };
+
+    let kind = match first {
+        Value::String(kind) => kind,
+        other => { return error("type-error", &format!("Expected an error kind, got '{}'", other), pos); }
+    };
+
+    Ok(Error::new(kind, message, args.next().unwrap_or(Value::Nil), pos))
+}
+
+fn is_keyword(expr: &expr::Expr, keyword: &str) -> bool {
+    matches!(&expr.data, expr::ExprData::Keyword(k) if k == keyword)
+}
+
+pub fn call_function(function: Value, args: Vec<Value>, pos: util::Position) -> Result<Value, Unwind> {
+    let name = function_name(&function);
+    call(function, name, args, pos)
+}
+
+// The name of a function in tracebacks, when it isn't called through a variable
+fn function_name(function: &Value) -> String {
+    let Value::Function(function) = function else {
+        return "fn".to_owned();
+    };
+
+    match &**function {
+        Function::UserFn { name: Some(name), .. } => name.clone(),
+        Function::UserFn { .. } => "fn".to_owned(),
+        Function::NativeFn(native) => native.name.clone(),
+        Function::StructFn { def, kind: StructFnKind::Constructor } => format!("make-{}", def.name),
+        Function::StructFn { def, kind: StructFnKind::Predicate } => format!("{}?", def.name),
+        Function::StructFn { def, kind: StructFnKind::Accessor(i) } => format!("{}-{}", def.name, def.fields[*i]),
+    }
+}
+
+// Calls a function with a frame for it on the call stack.
+// Tail calls made by the function are run by this same loop, each replacing the frame of the one before.
+fn call(function: Value, name: String, args: Vec<Value>, pos: util::Position) -> Result<Value, Unwind> {
+    let depth = CALL_STACK.with(|stack| stack.borrow().len());
+
+    if let Some(reason) = stack_exhausted(depth) {
+        return error("stack-overflow", &format!("Stack overflow; {} when calling '{}'", reason, name), pos);
+    }
+
+    CALL_STACK.with(|stack| stack.borrow_mut().push(Frame { function: name, pos: pos.clone() }));
+
+    let mut next = (function, args, pos);
+
+    let result = loop {
+        let (function, args, pos) = next;
+
+        match call_frame(function, args, pos) {
+            Err(Unwind::TailCall(t)) => {
+                let t = *t;
+                let frame = Frame { function: t.name, pos: t.pos.clone() };
+
+                CALL_STACK.with(|stack| {
+                    if let Some(top) = stack.borrow_mut().last_mut() {
+                        *top = frame;
+                    }
+                });
+
+                next = (t.function, t.args, t.pos);
+            }
+
+            result => break result,
+        }
+    };
+
+    CALL_STACK.with(|stack| stack.borrow_mut().pop());
+    result
+}
+
+fn call_frame(function: Value, args: Vec<Value>, pos: util::Position) -> Result<Value, Unwind> {
+    let function = match function {
+        Value::Function(f) => f,
+        other => { return error("type-error", &format!("Value '{}' isn't a function", other), pos); }
+    };
+
+    match &*function {
+        Function::UserFn { params, body, env: fn_env, .. } => {
+            if args.len() != params.len() {
+                return error("arity-error", &format!("Invalid number of arguments; expected {}, got {}", params.len(), args.len()), pos);
+            }
+
+            let mut new_env = env::Environment::from_enclosing(fn_env.clone());
+
+            for (i, (param, arg)) in params.iter().zip(args).enumerate() {
+                if !param.bind(&arg, &mut new_env) {
+                    return error("match-error", &format!("Argument {} ('{}') doesn't match the pattern of its parameter", i + 1, arg), pos);
+                }
+            }
+
+            match within(Enclosing { function: true, ..Enclosing::default() }, || execute_tail(body.clone(), &mut new_env)) {
+                Err(Unwind::Return(value, _)) => Ok(value),
+                Err(unwind) => Err(escaped(unwind)),
+                Ok(value) => Ok(value),
+            }
+        }
+
+        Function::NativeFn(native) => {
+            if !native.arity.accepts(args.len()) {
+                return error("arity-error", &format!("Invalid number of arguments; expected {}, got {}", native.arity, args.len()), pos);
+            }
+
+            let result = (native.call)(&mut Ctx { name: native.name.clone(), pos: pos.clone() }, args).map_err(Unwind::Error)?;
+            check_size(&result, &pos)?;
+
+            Ok(result)
+        }
+
+        Function::StructFn { def, kind } => {
+            let arity = match kind {
+                StructFnKind::Constructor => def.fields.len(),
+                _ => 1,
+            };
+
+            if args.len() != arity {
+                return error("arity-error", &format!("Invalid number of arguments; expected {}, got {}", arity, args.len()), pos);
+            }
+
+            if let StructFnKind::Constructor = kind {
+                let record = Value::Record(Record { def: def.clone(), values: Rc::new(args) });
+                check_size(&record, &pos)?;
+
+                return Ok(record);
+            }
+
+            let record = match &args[0] {
+                Value::Record(r) if Rc::ptr_eq(&r.def, def) => Some(r),
+                _ => None,
+            };
+
+            match (kind, record) {
+                (StructFnKind::Accessor(i), Some(r)) => Ok(r.values[*i].clone()),
+                (StructFnKind::Accessor(i), None) => error("type-error", &format!("Function '{}-{}' expected a '{}', got '{}'", def.name, def.fields[*i], def.name, args[0]), pos),
+                (_, record) => Ok(Value::Bool(record.is_some())),
+            }
+        }
     }
 }
@@ -11,6 +11,8 @@ pub struct Lexer {
 
   start_pos: util::Position,
   current_pos: util::Position,
+
+  errors: Vec<util::SyntaxError>,
 }
 
 impl Lexer {
@@ -24,10 +26,13 @@ impl Lexer {
 
         start_pos: util::Position::default(),
         current_pos: util::Position::default(),
+
+        errors: Vec::new(),
       }
     }
 
-    pub fn lex(&mut self) -> Result<Vec<token::Token>, ()> {
+    // Splits the input into tokens, or returns the mistakes found, in order
+    pub fn lex(&mut self) -> Result<Vec<token::Token>, Vec<util::SyntaxError>> {
       let mut had_error = false;
       
       while !self.is_at_end() {
@@ -40,7 +45,7 @@ impl Lexer {
       }
 
       if had_error {
-        Err(())
+        Err(std::mem::take(&mut self.errors))
       }
       else {
         Ok(self.tokens.clone())
@@ -54,6 +59,22 @@ impl Lexer {
 
         '(' => self.add_token(token::TokenKind::LParen),
         ')' => self.add_token(token::TokenKind::RParen),
+        '{' => self.add_token(token::TokenKind::LBrace),
+        '}' => self.add_token(token::TokenKind::RBrace),
+        '[' => self.add_token(token::TokenKind::LBracket),
+        ']' => self.add_token(token::TokenKind::RBracket),
+
+        '#' if self.peek() == Some('{') => {
+          self.advance();
+          self.add_token(token::TokenKind::HashLBrace);
+        }
+
+        '#' => self.record_or_identifier()?,
+
+        // comments run until the end of the line
+        ';' => {
+          while !self.is_at_end() && self.peek()? != '\n' { self.advance(); }
+        }
 
         '\n' => {
           self.current_pos.line += 1;
@@ -83,6 +104,21 @@ impl Lexer {
       Some(())
     }
 
+    // '#point{' starts a record literal; anything else starting with '#' is an identifier
+    fn record_or_identifier(&mut self) -> Option<()> {
+      let mut end = self.current;
+      while end < self.input.len() && is_identifier(self.input[end]) { end += 1; }
+
+      if end == self.current || self.input.get(end) != Some(&'{') {
+        return self.identifier();
+      }
+
+      while self.current <= end { self.advance(); }
+
+      self.add_token_literal(token::TokenKind::RecordLBrace, self.slice_range(self.start + 1, end), self.start_pos.clone());
+      Some(())
+    }
+
     fn number(&mut self) -> Option<()> {
       while !self.is_at_end() && self.peek()?.is_numeric() { self.advance(); }
 
@@ -95,7 +131,7 @@ impl Lexer {
       let slice = self.slice_input();
 
       if slice.parse::<f64>().is_err() {
-        return util::print_error(&format!("Invalid number literal: '{}'", slice), self.start_pos.clone());
+        return self.error(&format!("Invalid number literal: '{}'", slice), self.start_pos.clone());
       }
 
       self.add_token(token::TokenKind::Number);
@@ -105,7 +141,7 @@ impl Lexer {
     fn string(&mut self) -> Option<()> {
       while !self.is_at_end() && self.peek()? != '"' {
         if self.peek()? == '\n' {
-          return util::print_error("Unterminated string", self.current_pos.clone());
+          return self.error("Unterminated string", self.current_pos.clone());
         }
 
         self.advance();
@@ -119,6 +155,12 @@ impl Lexer {
 
     // ---
 
+    #[must_use="Use this to return from a function, either by using '?' or a return statement"]
+    fn error<T>(&mut self, message: &str, pos: Position) -> Option<T> {
+      self.errors.push(util::SyntaxError { message: message.to_owned(), pos });
+      None
+    }
+
     fn is_at_end(&self) -> bool {
       self.current >= self.input.len()
     }
@@ -174,6 +216,11 @@ fn is_identifier(c: char) -> bool {
       '"'
     | '('
     | ')'
+    | '{'
+    | '}'
+    | '['
+    | ']'
+    | ';'
     | '\n' => false,
     
     c if c.is_whitespace() => false,
@@ -182,7 +229,10 @@ fn is_identifier(c: char) -> bool {
 }
 
 fn is_keyword(s: &str) -> bool {
-  matches!(s, "set" | "fn" | "defn" | "if" | "while" | "true" | "false" | "nil")
+  matches!(
+    s,
+    "set" | "set!" | "fn" | "defn" | "defstruct" | "if" | "cond" | "case" | "when" | "unless" | "else" | "do" | "let" | "match" | "while" | "dotimes" | "for" | "for-each" | "doseq" | "loop" | "recur" | "break" | "continue" | "return" | "throw" | "raise" | "try" | "catch" | "finally" | "import" | "require" | "export" | "true" | "false" | "nil"
+  )
 }
 
 fn is_operator(s: &str) -> bool {
@@ -0,0 +1,133 @@
+mod util;
+mod token;
+mod lexer;
+mod expr;
+mod parser;
+mod pattern;
+mod persistent;
+mod convert;
+mod env;
+mod module;
+mod prelude;
+mod process;
+mod interpreter;
+
+use std::{path::{Path, PathBuf}, rc::Rc};
+
+pub use env::{set_args, Capabilities, Capability};
+pub use interpreter::{set_max_call_depth, set_stack_size, Arity, Ctx, Error, Foreign, Frame, Function, Limits, NativeFn, Record, StructDef, Value, DEFAULT_MAX_CALL_DEPTH};
+pub use convert::{FromValue, IntoNativeResult, IntoValue, TypedNative};
+pub use persistent::{List, Map, Set, Vector};
+pub use util::Position;
+
+// An interpreter with its own global environment, for embedding Lispen in Rust programs.
+// The modules it imports are its own too, so two interpreters never share their state.
+//
+// The call stack is kept per thread, so an interpreter should be used from the thread that created it. Deep recursion needs a big stack: run it on a thread
+// spawned with a large 'stack_size', and call 'set_stack_size' there so it raises an error before overflowing.
+pub struct Interpreter {
+    env: env::Environment,
+    limits: Limits,
+}
+
+impl Interpreter {
+    // An interpreter with every capability
+    pub fn new() -> Self {
+        Self::with_capabilities(Capabilities::ALL)
+    }
+
+    // An interpreter whose scripts only get the native functions of the given capabilities;
+    // with 'Capabilities::NONE', they can't run programs, touch files, read the standard input, the clock or environment variables.
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
+        Self { env: env::Environment::new(capabilities), limits: Limits::default() }
+    }
+
+    // Like 'with_capabilities', without the functions of the prelude, in the interpreter or the modules it imports
+    pub fn without_prelude(capabilities: Capabilities) -> Self {
+        Self { env: env::Environment::without_prelude(capabilities), limits: Limits::default() }
+    }
+
+    // Limits every later call to 'eval_str', 'eval_file' and 'call_function' of this interpreter, each counted on its own
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Directories where the modules it imports are looked for, after the directory of the file importing them
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.env.modules().borrow_mut().add_search_path(path.into());
+    }
+
+    // Runs every expression in 'source', evaluating to the last one.
+    // If it can't be parsed, nothing runs, and the error is a "parse-error" with the first mistake found;
+    // its data holds all of them.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Rc<Error>> {
+        let exprs = lexer::Lexer::new(source).lex().and_then(|tokens| parser::Parser::new(tokens).parse());
+
+        let exprs = match exprs {
+            Ok(exprs) => exprs,
+            Err(errors) => { return Err(interpreter::parse_error(&errors, None, Position::default())); }
+        };
+
+        let env = &mut self.env;
+        interpreter::with_limits(self.limits, || interpreter::run(exprs, env)).map_err(interpreter::top_level_error)
+    }
+
+    // Runs a file in the global environment, evaluating to its last expression.
+    // Modules it imports are looked for relative to it.
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<Value, Rc<Error>> {
+        let path = std::fs::canonicalize(path.as_ref()).unwrap_or_else(|_| path.as_ref().to_owned());
+        let env = &mut self.env;
+
+        match interpreter::with_limits(self.limits, || module::run_file(&path, env, Position::default())) {
+            Ok((value, _)) => Ok(value),
+            Err(unwind) => Err(interpreter::top_level_error(unwind)),
+        }
+    }
+
+    // Calls the function bound to the global 'name'
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Rc<Error>> {
+        let function = match self.env.get_variable(name) {
+            Some(function) => function,
+            None => { return Err(Error::new("name-error".to_owned(), format!("Variable '{}' doesn't exist in this scope", name), Value::Nil, Position::default())); }
+        };
+
+        interpreter::with_limits(self.limits, || interpreter::call_function(function, args, Position::default())).map_err(interpreter::top_level_error)
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.env.define_variable(name.to_owned(), value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.env.get_variable(name)
+    }
+
+    // Defines a global native function, which takes any number of arguments.
+    // The closure can capture state:
+    //
+    //     let mut lisp = Interpreter::new();
+    //     let db = Rc::new(Database::open());
+    //     lisp.register("db-query", move |ctx, args| db.query(ctx, args));
+    pub fn register(&mut self, name: &str, call: impl Fn(&mut Ctx, Vec<Value>) -> Result<Value, Rc<Error>> + 'static) {
+        self.register_with(name, Arity::AtLeast(0), "", call);
+    }
+
+    // Like 'register', checking the number of arguments before calling it, and with documentation for 'doc'
+    pub fn register_with(&mut self, name: &str, arity: Arity, doc: &str, call: impl Fn(&mut Ctx, Vec<Value>) -> Result<Value, Rc<Error>> + 'static) {
+        self.define_global(name, Value::Function(Rc::new(Function::native(name, arity, doc, call))));
+    }
+
+    // Defines a global native function from a Rust function with typed arguments, converted with FromValue.
+    // It's called with exactly as many arguments as it takes, and raises a "type-error" when one can't be converted:
+    //
+    //     lisp.register_fn("split", "Splits a string", |s: String, sep: String| -> Vec<String> { ... });
+    pub fn register_fn<Args, F: TypedNative<Args> + 'static>(&mut self, name: &str, doc: &str, function: F) {
+        self.register_with(name, F::arity(), doc, move |ctx, args| function.call_typed(ctx, args));
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
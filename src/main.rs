@@ -1,33 +1,158 @@
-mod util;
-mod token;
-mod lexer;
-mod expr;
-mod parser;
-mod env;
-mod interpreter;
+use std::{io::{self, Write}, path::PathBuf, time::Duration};
+
+use lispen::{Capabilities, Interpreter, Limits};
+
+// Evaluation runs on its own thread, with a stack big enough for deep recursion
+const STACK_SIZE: usize = 1 << 30;
 
 fn main() {
-    repl();
+    let mut args = std::env::args().skip(1);
+    let mut max_depth = lispen::DEFAULT_MAX_CALL_DEPTH;
+    let mut search_paths: Vec<PathBuf> = Vec::new();
+    let mut script = None;
+    let mut script_args = Vec::new();
+    let mut use_prelude = true;
+    let mut capabilities = Capabilities::ALL;
+    let mut limits = Limits::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-depth" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(depth) => max_depth = depth,
+                None => {
+                    eprintln!("Expected a number after '--max-depth'");
+                    return;
+                }
+            },
+
+            "--no-prelude" => use_prelude = false,
+
+            // untrusted scripts: no processes, files, standard input, clock or environment variables
+            "--sandbox" => capabilities = Capabilities::NONE,
+
+            "--max-steps" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(steps) => limits.max_steps = Some(steps),
+                None => {
+                    eprintln!("Expected a number after '--max-steps'");
+                    return;
+                }
+            },
+
+            "--max-size" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(size) => limits.max_size = Some(size),
+                None => {
+                    eprintln!("Expected a number after '--max-size'");
+                    return;
+                }
+            },
+
+            "--timeout" => match args.next().and_then(|n| n.parse().ok()).and_then(|secs| Duration::try_from_secs_f64(secs).ok()) {
+                Some(timeout) => limits.timeout = Some(timeout),
+                None => {
+                    eprintln!("Expected a number of seconds after '--timeout'");
+                    return;
+                }
+            },
+
+            "--path" => match args.next() {
+                Some(path) => search_paths.push(path.into()),
+                None => {
+                    eprintln!("Expected a directory after '--path'");
+                    return;
+                }
+            },
+
+            other if other.starts_with("--") => {
+                eprintln!("Unknown argument '{}'", other);
+                return;
+            }
+
+            // everything after the script is for the script
+            _ => {
+                script = Some(PathBuf::from(arg));
+                script_args.extend(args.by_ref());
+            }
+        }
+    }
+
+    // directories in LISPEN_PATH come after the ones given with '--path'
+    if let Some(paths) = std::env::var_os("LISPEN_PATH") {
+        search_paths.extend(std::env::split_paths(&paths));
+    }
+
+    let thread = std::thread::Builder::new().stack_size(STACK_SIZE).spawn(move || {
+        lispen::set_max_call_depth(max_depth);
+        lispen::set_stack_size(STACK_SIZE);
+        lispen::set_args(script_args);
+
+        let mut interpreter = match use_prelude {
+            true => Interpreter::with_capabilities(capabilities),
+            false => Interpreter::without_prelude(capabilities),
+        };
+
+        interpreter.set_limits(limits);
+
+        for path in search_paths {
+            interpreter.add_search_path(path);
+        }
+
+        match script {
+            Some(path) => run_script(interpreter, path),
+            None => repl(interpreter),
+        }
+    });
+
+    let code = thread.unwrap().join().unwrap();
+
+    if code != 0 {
+        std::process::exit(code);
+    }
 }
 
-fn repl() {
+// Returns the exit code: the one given to 'exit', or 1 if the script stopped with an error
+fn run_script(mut interpreter: Interpreter, path: PathBuf) -> i32 {
+    match interpreter.eval_file(path) {
+        Ok(_) => 0,
+        Err(e) => match e.exit_code {
+            Some(code) => code,
+            None => {
+                eprintln!("{}", e);
+                1
+            }
+        },
+    }
+}
+
+fn repl(mut interpreter: Interpreter) -> i32 {
     println!("Lispen REPL\n");
-    let mut env = env::Environment::new();
 
     loop {
-        let mut input = String::new();
-        util::input("> ", &mut input);
+        let mut line = String::new();
+        input("> ", &mut line);
 
-        if input == "exit" {
-            return;
+        if line == "exit" {
+            return 0;
         }
 
-        let lexer_res = lexer::Lexer::new(&input).lex();
-        if lexer_res.is_err() { continue; }
-
-        let parser_res = parser::Parser::new(lexer_res.unwrap()).parse();
-        if parser_res.is_err() { continue; }
+        if line.is_empty() {
+            continue;
+        }
 
-        interpreter::interpret(parser_res.unwrap(), &mut env);
+        match interpreter.eval_str(&line) {
+            Ok(value) => println!("< {}", value),
+            Err(e) => match e.exit_code {
+                Some(code) => { return code; }
+                None => eprintln!("{}", e),
+            },
+        }
     }
 }
+
+fn input(prompt: &str, out: &mut String) {
+    print!("{}", prompt);
+
+    io::stdout().flush().unwrap();
+    io::stdin().read_line(out).unwrap();
+
+    *out = out.trim().to_owned();
+}
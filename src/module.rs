@@ -0,0 +1,143 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use crate::{env, interpreter::{self, Unwind, Value}, lexer, parser, persistent, util};
+
+// Modules are source files, each run once in its own top-level environment, with the capabilities of the program importing it.
+// What they export is kept as a map from names to values, which 'import' binds to the module's name,
+// so 'strings/trim' finds 'trim' in the exports of 'strings'.
+
+pub const EXTENSION: &str = "lsp";
+
+// A file being run, and the names it exported so far (None if it has no 'export' form)
+struct Loading {
+    path: PathBuf,
+    exports: Option<Vec<String>>,
+}
+
+// The modules of a program (see 'env::Environment::modules'): the ones it loaded, the files being run, and where to look for more
+#[derive(Default)]
+pub struct Modules {
+    loaded: HashMap<PathBuf, Value>,
+    loading: Vec<Loading>,
+    search_paths: Vec<PathBuf>,
+}
+
+impl Modules {
+    // Directories where modules are looked for, after the directory of the file importing them
+    pub fn add_search_path(&mut self, path: PathBuf) {
+        self.search_paths.push(path);
+    }
+}
+
+// The exports of the module at 'path', loading it if it wasn't yet
+pub fn import(path: &str, importer: &env::Environment, pos: util::Position) -> Result<Value, Unwind> {
+    if !importer.capabilities().has(env::Capability::Filesystem) {
+        return interpreter::error("import-error", &format!("Can't import '{}' without access to the filesystem", path), pos);
+    }
+
+    let resolved = resolve(path, importer, &pos)?;
+
+    if let Some(module) = importer.modules().borrow().loaded.get(&resolved).cloned() {
+        return Ok(module);
+    }
+
+    let cycle = {
+        let modules = importer.modules().borrow();
+
+        modules.loading.iter().position(|l| l.path == resolved).map(|start| {
+            modules.loading[start..].iter().map(|l| l.path.display().to_string()).collect::<Vec<_>>()
+        })
+    };
+
+    if let Some(mut cycle) = cycle {
+        cycle.push(resolved.display().to_string());
+        return interpreter::error("import-error", &format!("Circular import: {}", cycle.join(" -> ")), pos);
+    }
+
+    let mut env = env::Environment::from_enclosing(importer.for_module());
+    let (_, exported) = run_file(&resolved, &mut env, pos.clone())?;
+
+    let names = match exported {
+        Some(names) => names,
+        None => env.own_variables().into_iter().map(|(name, _)| name).collect(),
+    };
+
+    let mut exports = persistent::Map::new();
+
+    for name in names {
+        match env.get_variable(&name) {
+            Some(value) => exports.insert_mut(Value::String(name), value),
+            None => { return interpreter::error("name-error", &format!("Module '{}' exports '{}', which it doesn't define", path, name), pos); }
+        }
+    }
+
+    let module = Value::Map(exports);
+    importer.modules().borrow_mut().loaded.insert(resolved, module.clone());
+
+    Ok(module)
+}
+
+// Runs every expression of a file in 'env', returning the value of the last one
+// and the names it exported, if it has an 'export' form.
+pub fn run_file(path: &Path, env: &mut env::Environment, pos: util::Position) -> Result<(Value, Option<Vec<String>>), Unwind> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => { return interpreter::error("io-error", &format!("Couldn't read '{}': {}", path.display(), e), pos); }
+    };
+
+    let exprs = lexer::Lexer::new(&source).lex().and_then(|tokens| parser::Parser::new(tokens).parse());
+
+    let exprs = match exprs {
+        Ok(exprs) => exprs,
+        Err(errors) => { return Err(Unwind::Error(interpreter::parse_error(&errors, Some(&path.display().to_string()), pos))); }
+    };
+
+    env.modules().borrow_mut().loading.push(Loading { path: path.to_owned(), exports: None });
+    let result = interpreter::run(exprs, env);
+    let loaded = env.modules().borrow_mut().loading.pop();
+
+    Ok((result?, loaded.and_then(|l| l.exports)))
+}
+
+// Called by '(export name...)'; only allowed while a file is being run
+pub fn export(names: Vec<String>, env: &env::Environment, pos: util::Position) -> Result<(), Unwind> {
+    match env.modules().borrow_mut().loading.last_mut() {
+        Some(l) => l.exports.get_or_insert_with(Vec::new).extend(names),
+        None => { return interpreter::error("syntax-error", "'export' can only be used in a module", pos); }
+    }
+
+    Ok(())
+}
+
+// The name a module is bound to when it's imported without ':as', e.g. 'strings' for "lib/strings.lsp"
+pub fn default_name(path: &str) -> String {
+    Path::new(path).file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| path.to_owned())
+}
+
+// Finds a module relative to the file importing it (or the working directory), and then in the search paths.
+fn resolve(path: &str, importer: &env::Environment, pos: &util::Position) -> Result<PathBuf, Unwind> {
+    let mut file = PathBuf::from(path);
+
+    if file.extension().is_none() {
+        file.set_extension(EXTENSION);
+    }
+
+    let dirs = {
+        let modules = importer.modules().borrow();
+        let importer_dir = modules.loading.last().and_then(|l| l.path.parent().map(Path::to_owned));
+
+        let mut dirs = vec![importer_dir.unwrap_or_default()];
+        dirs.extend(modules.search_paths.iter().cloned());
+        dirs
+    };
+
+    for dir in dirs {
+        if let Ok(found) = fs::canonicalize(dir.join(&file)) {
+            if found.is_file() {
+                return Ok(found);
+            }
+        }
+    }
+
+    interpreter::error("import-error", &format!("Couldn't find module '{}'", path), pos.clone())
+}
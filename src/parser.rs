@@ -3,14 +3,16 @@ use crate::{expr, token, util};
 pub struct Parser {
     input: Vec<token::Token>,
     current: usize,
+    errors: Vec<util::SyntaxError>,
 }
 
 impl Parser {
     pub fn new(input: Vec<token::Token>) -> Self {
-        Self { input, current: 0 }
+        Self { input, current: 0, errors: Vec::new() }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<expr::Expr>, ()> {
+    // Parses every expression, or returns the mistakes found, in order
+    pub fn parse(&mut self) -> Result<Vec<expr::Expr>, Vec<util::SyntaxError>> {
         let mut exprs: Vec<expr::Expr> = Vec::new();
         let mut had_error = false;
 
@@ -24,7 +26,7 @@ impl Parser {
         }
 
         if had_error {
-            Err(())
+            Err(std::mem::take(&mut self.errors))
         } else {
             Ok(exprs)
         }
@@ -32,6 +34,12 @@ impl Parser {
 
     // ---
 
+    #[must_use="Use this to return from a function, either by using '?' or a return statement"]
+    fn error<T>(&mut self, message: &str, pos: util::Position) -> Option<T> {
+        self.errors.push(util::SyntaxError { message: message.to_owned(), pos });
+        None
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.input.len()
     }
@@ -54,10 +62,20 @@ impl Parser {
 
         match self.advance()?.kind {
             token::TokenKind::LParen => self.list(false),
+            token::TokenKind::LBrace => self.map(),
+            token::TokenKind::LBracket => {
+                let pos = t.pos.clone();
+                Some(expr::Expr::new(t.pos, expr::ExprData::Vector(self.items(token::TokenKind::RBracket, "]", pos)?)))
+            }
+            token::TokenKind::RecordLBrace => self.record(t),
+            token::TokenKind::HashLBrace => {
+                let pos = t.pos.clone();
+                Some(expr::Expr::new(t.pos, expr::ExprData::Set(self.items(token::TokenKind::RBrace, "}", pos)?)))
+            }
             token::TokenKind::Identifier => {
                 if t.lexeme == "'" {
                     if !matches!(self.advance()?.kind, token::TokenKind::LParen) {
-                        util::print_error(
+                        self.error(
                             &format!("Expected '(', got '{}'", self.peek()?.lexeme),
                             t.pos,
                         )?;
@@ -84,7 +102,7 @@ impl Parser {
                 _ => Some(expr::Expr::new(t.pos, expr::ExprData::Keyword(t.lexeme))),
             },
 
-            _ => util::print_error(&format!("Invalid expression: '{}'", t.lexeme), t.pos)
+            _ => self.error(&format!("Invalid expression: '{}'", t.lexeme), t.pos)
         }
     }
 
@@ -95,7 +113,7 @@ impl Parser {
         while !matches!(
             match self.peek() {
                 Some(t) => t.kind,
-                None => { return util::print_error("Expected ')' after list", pos); }
+                None => { return self.error("Expected ')' after list", pos); }
             },
             token::TokenKind::RParen
         ) {
@@ -103,13 +121,13 @@ impl Parser {
 
             if let expr::ExprData::Operator(o) = expr.data.clone() {
                 if !args.is_empty() {
-                    util::print_error(&format!("Operator '{}' cannot be used as value", o), pos.clone())?;
+                    self.error(&format!("Operator '{}' cannot be used as value", o), pos.clone())?;
                 }
             }
 
             if let expr::ExprData::Keyword(k) = expr.data.clone() {
                 if !args.is_empty() {
-                    util::print_error(&format!("Keyword '{}' cannot be used as value", k), pos.clone())?;
+                    self.error(&format!("Keyword '{}' cannot be used as value", k), pos.clone())?;
                 }
             }
 
@@ -119,4 +137,76 @@ impl Parser {
         self.advance();
         Some(expr::Expr::new(pos, expr::ExprData::List(args, is_quote)))
     }
+
+    fn map(&mut self) -> Option<expr::Expr> {
+        let pos = self.peek()?.pos;
+        let items = self.items(token::TokenKind::RBrace, "}", pos.clone())?;
+
+        if !items.len().is_multiple_of(2) {
+            return self.error("Map literal must contain an even number of forms; every key needs a value", pos);
+        }
+
+        let mut pairs: Vec<(expr::Expr, expr::Expr)> = Vec::new();
+        let mut iter = items.into_iter();
+
+        while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+            pairs.push((key, value));
+        }
+
+        Some(expr::Expr::new(pos, expr::ExprData::Map(pairs)))
+    }
+
+    // #name{field value ...}
+    fn record(&mut self, open: token::Token) -> Option<expr::Expr> {
+        let items = self.items(token::TokenKind::RBrace, "}", open.pos.clone())?;
+
+        if !items.len().is_multiple_of(2) {
+            return self.error("Record literal must contain an even number of forms; every field needs a value", open.pos);
+        }
+
+        let mut fields: Vec<(String, expr::Expr)> = Vec::new();
+        let mut iter = items.into_iter();
+
+        while let (Some(field), Some(value)) = (iter.next(), iter.next()) {
+            match field.data {
+                expr::ExprData::Identifier(name) => fields.push((name, value)),
+                _ => { return self.error("Record field names must be identifiers", field.pos); }
+            }
+        }
+
+        Some(expr::Expr::new(open.pos, expr::ExprData::Record(open.lexeme, fields)))
+    }
+
+    // Parses the elements of a collection literal up to (and including) its closing token.
+    // Unlike lists, none of the elements can be an operator or a keyword.
+    fn items(&mut self, closing: token::TokenKind, closing_lexeme: &str, pos: util::Position) -> Option<Vec<expr::Expr>> {
+        let mut items: Vec<expr::Expr> = Vec::new();
+
+        loop {
+            match self.peek() {
+                Some(t) if t.kind == closing => break,
+                Some(_) => {}
+                None => { return self.error(&format!("Expected '{}' after collection literal", closing_lexeme), pos); }
+            }
+
+            let expr = self.expr()?;
+
+            match expr.data.clone() {
+                expr::ExprData::Operator(o) => {
+                    self.error(&format!("Operator '{}' cannot be used as value", o), pos.clone())?;
+                }
+
+                expr::ExprData::Keyword(k) => {
+                    self.error(&format!("Keyword '{}' cannot be used as value", k), pos.clone())?;
+                }
+
+                _ => {}
+            }
+
+            items.push(expr);
+        }
+
+        self.advance();
+        Some(items)
+    }
 }
@@ -0,0 +1,199 @@
+use crate::{env, expr, interpreter::{self, Unwind, Value}};
+
+// Patterns describe the shape of a value, binding names to the parts they match:
+//
+// _                 matches anything
+// x                 matches anything, binding it to 'x'
+// 1, "s", true, nil matches an equal value
+// (a b & rest)      matches a list, binding 'rest' to the remaining elements
+// [a b & rest]      the same, for vectors
+// {"key" p}         matches a map with the key, whose value matches 'p'
+// {:keys [a b]}     matches a map, binding 'a' and 'b' to the values of the keys "a" and "b" (or nil)
+// #point{x p}       matches a 'point' record whose 'x' field matches 'p'
+#[derive(Clone)]
+pub enum Pattern {
+    Wildcard,
+    Bind(String),
+    Literal(Value),
+    List(Vec<Pattern>, Option<Box<Pattern>>),
+    Vector(Vec<Pattern>, Option<Box<Pattern>>),
+    Map(Vec<(Value, Pattern)>, Vec<String>),
+    Record(String, Vec<(String, Pattern)>),
+}
+
+impl Pattern {
+    pub fn from_expr(expr: &expr::Expr) -> Result<Pattern, Unwind> {
+        match &expr.data {
+            expr::ExprData::Identifier(name) if name == "_" => Ok(Pattern::Wildcard),
+            expr::ExprData::Identifier(name) if name == "&" => {
+                interpreter::error("syntax-error", "'&' must be followed by a single pattern at the end of a list or vector pattern", expr.pos.clone())
+            }
+            expr::ExprData::Identifier(name) => Ok(Pattern::Bind(name.clone())),
+
+            expr::ExprData::Number(_)
+            | expr::ExprData::String(_)
+            | expr::ExprData::Bool(_)
+            | expr::ExprData::Nil => Ok(Pattern::Literal(literal(expr)?)),
+
+            expr::ExprData::List(items, _) => {
+                let (items, rest) = sequence(items)?;
+                Ok(Pattern::List(items, rest))
+            }
+
+            expr::ExprData::Vector(items) => {
+                let (items, rest) = sequence(items)?;
+                Ok(Pattern::Vector(items, rest))
+            }
+
+            expr::ExprData::Map(pairs) => {
+                let mut entries = Vec::new();
+                let mut keys = Vec::new();
+
+                for (key, value) in pairs {
+                    if matches!(&key.data, expr::ExprData::Identifier(k) if k == ":keys") {
+                        keys.extend(key_names(value)?);
+                    } else {
+                        entries.push((literal(key)?, Pattern::from_expr(value)?));
+                    }
+                }
+
+                Ok(Pattern::Map(entries, keys))
+            }
+
+            expr::ExprData::Record(name, fields) => {
+                let mut patterns = Vec::new();
+
+                for (field, value) in fields {
+                    patterns.push((field.clone(), Pattern::from_expr(value)?));
+                }
+
+                Ok(Pattern::Record(name.clone(), patterns))
+            }
+
+            _ => interpreter::error("syntax-error", "Invalid pattern; expected an identifier, a literal or a collection of patterns", expr.pos.clone())
+        }
+    }
+
+    // Binds every name in the pattern into 'env', returning whether the value matched.
+    // Names bound before a mismatch are left in 'env', so callers should use a fresh scope.
+    pub fn bind(&self, value: &Value, env: &mut env::Environment) -> bool {
+        match (self, value) {
+            (Pattern::Wildcard, _) => true,
+            (Pattern::Bind(name), value) => {
+                env.define_variable(name.clone(), value.clone());
+                true
+            }
+            (Pattern::Literal(literal), value) => literal == value,
+
+            (Pattern::List(items, rest), Value::List(list)) => {
+                if !fits(items.len(), rest.is_some(), list.len()) {
+                    return false;
+                }
+
+                let mut remaining = list.clone();
+
+                for pattern in items {
+                    let matched = pattern.bind(remaining.first().unwrap_or(&Value::Nil), env);
+                    remaining = remaining.rest();
+
+                    if !matched {
+                        return false;
+                    }
+                }
+
+                rest.as_ref().is_none_or(|r| r.bind(&Value::List(remaining), env))
+            }
+
+            (Pattern::Vector(items, rest), Value::Vector(vector)) => {
+                if !fits(items.len(), rest.is_some(), vector.len()) {
+                    return false;
+                }
+
+                let mut values = vector.iter();
+
+                if !items.iter().all(|pattern| values.next().is_some_and(|v| pattern.bind(v, env))) {
+                    return false;
+                }
+
+                rest.as_ref().is_none_or(|r| r.bind(&Value::Vector(values.cloned().collect()), env))
+            }
+
+            (Pattern::Map(entries, keys), Value::Map(map)) => {
+                for name in keys {
+                    let value = map.get(&Value::String(name.clone())).cloned().unwrap_or(Value::Nil);
+                    env.define_variable(name.clone(), value);
+                }
+
+                entries.iter().all(|(key, pattern)| match map.get(key) {
+                    Some(v) => pattern.bind(v, env),
+                    None => false,
+                })
+            }
+
+            (Pattern::Record(name, fields), Value::Record(record)) => {
+                record.def.name == *name
+                    && fields.iter().all(|(field, pattern)| match record.field_index(field) {
+                        Some(i) => pattern.bind(&record.values[i], env),
+                        None => false,
+                    })
+            }
+
+            _ => false,
+        }
+    }
+}
+
+fn fits(patterns: usize, has_rest: bool, len: usize) -> bool {
+    if has_rest { len >= patterns } else { len == patterns }
+}
+
+fn literal(expr: &expr::Expr) -> Result<Value, Unwind> {
+    match &expr.data {
+        expr::ExprData::Number(n) => Ok(Value::Number(*n)),
+        expr::ExprData::String(s) => Ok(Value::String(s.clone())),
+        expr::ExprData::Bool(b) => Ok(Value::Bool(*b)),
+        expr::ExprData::Nil => Ok(Value::Nil),
+
+        _ => interpreter::error("syntax-error", "Invalid literal in pattern; expected a number, string, boolean or nil", expr.pos.clone())
+    }
+}
+
+// The names after ':keys', written as a vector or a list of identifiers
+fn key_names(expr: &expr::Expr) -> Result<Vec<String>, Unwind> {
+    let items = match &expr.data {
+        expr::ExprData::Vector(items) | expr::ExprData::List(items, _) => items,
+        _ => { return interpreter::error("syntax-error", "Expected a vector of names after ':keys'", expr.pos.clone()); }
+    };
+
+    let mut names = Vec::new();
+
+    for item in items {
+        match &item.data {
+            expr::ExprData::Identifier(name) => names.push(name.clone()),
+            _ => { return interpreter::error("syntax-error", "Expected a name in ':keys' list", item.pos.clone()); }
+        }
+    }
+
+    Ok(names)
+}
+
+// The patterns of a list or vector pattern, and the one after '&', if any
+#[allow(clippy::type_complexity)]
+fn sequence(items: &[expr::Expr]) -> Result<(Vec<Pattern>, Option<Box<Pattern>>), Unwind> {
+    let mut patterns = Vec::new();
+
+    for (i, item) in items.iter().enumerate() {
+        if matches!(&item.data, expr::ExprData::Identifier(name) if name == "&") {
+            if i + 2 != items.len() {
+                return interpreter::error("syntax-error", "'&' must be followed by a single pattern at the end of a list or vector pattern", item.pos.clone());
+            }
+
+            let rest = Pattern::from_expr(&items[i + 1])?;
+            return Ok((patterns, Some(Box::new(rest))));
+        }
+
+        patterns.push(Pattern::from_expr(item)?);
+    }
+
+    Ok((patterns, None))
+}
@@ -0,0 +1,155 @@
+use std::rc::Rc;
+
+// Singly linked list made of shared cons cells.
+pub struct List<T> {
+    head: Option<Rc<Node<T>>>,
+}
+
+struct Node<T> {
+    value: T,
+    next: Option<Rc<Node<T>>>,
+    // length of the list starting at this node
+    len: usize,
+}
+
+impl<T> List<T> {
+    pub fn new() -> Self {
+        Self { head: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.head.as_ref().map_or(0, |n| n.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn cons(&self, value: T) -> Self {
+        Self {
+            head: Some(Rc::new(Node {
+                value,
+                next: self.head.clone(),
+                len: self.len() + 1,
+            })),
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.head.as_ref().map(|n| &n.value)
+    }
+
+    pub fn rest(&self) -> Self {
+        Self {
+            head: self.head.as_ref().and_then(|n| n.next.clone()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.iter().nth(index)
+    }
+
+    // Whether both lists are the very same cells, not just equal ones.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.head, &other.head) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head.as_deref(),
+        }
+    }
+}
+
+impl<T> Clone for List<T> {
+    fn clone(&self) -> Self {
+        Self {
+            head: self.head.clone(),
+        }
+    }
+}
+
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The default drop would recurse once per cell, overflowing the stack on long lists.
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        let mut next = self.head.take();
+
+        while let Some(node) = next {
+            match Rc::try_unwrap(node) {
+                Ok(mut node) => next = node.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl<T> FromIterator<T> for List<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let items: Vec<T> = iter.into_iter().collect();
+        let mut list = List::new();
+
+        for item in items.into_iter().rev() {
+            list = list.cons(item);
+        }
+
+        list
+    }
+}
+
+pub struct Iter<'a, T> {
+    next: Option<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?;
+        self.next = node.next.as_deref();
+
+        Some(&node.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.next.map_or(0, |n| n.len);
+        (len, Some(len))
+    }
+}
+
+impl<T> ExactSizeIterator for Iter<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::List;
+
+    #[test]
+    fn shares_cells_with_older_lists() {
+        let list: List<u32> = (1..=3).collect();
+        let longer = list.cons(0);
+
+        assert!(longer.rest().ptr_eq(&list));
+        assert_eq!((longer.len(), longer.first(), longer.get(3)), (4, Some(&0), Some(&3)));
+        assert!(list.iter().copied().eq(1..=3));
+        assert!(List::<u32>::new().rest().is_empty());
+    }
+
+    #[test]
+    fn drops_long_lists_without_recursing() {
+        let list: List<u32> = (0..1_000_000).collect();
+        let rest = list.rest();
+
+        assert_eq!(list.len(), 1_000_000);
+        drop(list);
+        assert_eq!((rest.len(), rest.first()), (999_999, Some(&1)));
+    }
+}
@@ -0,0 +1,518 @@
+use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}, rc::Rc};
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+// Hash array mapped trie: every level consumes 5 bits of the key's hash, and each node
+// only stores the slots that are in use, indexed through a 32 bit bitmap.
+pub struct Map<K, V> {
+    root: Option<Rc<Node<K, V>>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+enum Node<K, V> {
+    Branch { bitmap: u32, entries: Vec<Entry<K, V>> },
+    // Keys whose whole hash is the same, only found once all the hash bits are used up.
+    Collision(Vec<(K, V)>),
+}
+
+#[derive(Clone)]
+enum Entry<K, V> {
+    Pair(K, V),
+    Child(Rc<Node<K, V>>),
+}
+
+// The default hasher has fixed keys, so the layout (and iteration order) is deterministic.
+fn hash_of<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn slot(hash: u64, shift: u32) -> (u32, u32) {
+    let bit = 1 << ((hash >> shift) & MASK);
+    (bit, bit - 1)
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Map<K, V> {
+    pub fn new() -> Self {
+        Self { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let hash = hash_of(key);
+        let mut node = self.root.as_deref()?;
+        let mut shift = 0;
+
+        loop {
+            match node {
+                Node::Branch { bitmap, entries } => {
+                    let (bit, below) = slot(hash, shift);
+
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+
+                    match &entries[(bitmap & below).count_ones() as usize] {
+                        Entry::Pair(k, v) => return if k == key { Some(v) } else { None },
+                        Entry::Child(child) => {
+                            node = child;
+                            shift += BITS;
+                        }
+                    }
+                }
+
+                Node::Collision(pairs) => return pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            }
+        }
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Returns a new map with 'key' bound to 'value'.
+    pub fn insert(&self, key: K, value: V) -> Self {
+        let mut map = self.clone();
+        map.insert_mut(key, value);
+        map
+    }
+
+    // Returns a new map without 'key'.
+    pub fn remove(&self, key: &K) -> Self {
+        let mut map = self.clone();
+        map.remove_mut(key);
+        map
+    }
+
+    // In-place versions, copying only the nodes that are shared with other maps.
+
+    pub fn insert_mut(&mut self, key: K, value: V) {
+        let hash = hash_of(&key);
+        let root = self.root.get_or_insert_with(|| Rc::new(Node::Branch { bitmap: 0, entries: Vec::new() }));
+
+        if insert_in(root, 0, hash, key, value) {
+            self.len += 1;
+        }
+    }
+
+    pub fn remove_mut(&mut self, key: &K) {
+        if !self.contains_key(key) {
+            return;
+        }
+
+        if let Some(root) = self.root.as_mut() {
+            remove_from(root, 0, hash_of(key), key);
+            self.len -= 1;
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let stack = match self.root.as_deref() {
+            Some(Node::Branch { entries, .. }) => vec![entries.iter()],
+            _ => Vec::new(),
+        };
+
+        Iter {
+            stack,
+            pairs: [].iter(),
+            remaining: self.len,
+        }
+    }
+
+    // Entries ordered by key, for when the hash order isn't good enough (e.g. printing).
+    pub fn sorted(&self) -> Vec<(&K, &V)>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<(&K, &V)> = self.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries
+    }
+}
+
+// Returns whether a new key was added.
+fn insert_in<K: Hash + Eq + Clone, V: Clone>(node: &mut Rc<Node<K, V>>, shift: u32, hash: u64, key: K, value: V) -> bool {
+    match Rc::make_mut(node) {
+        Node::Branch { bitmap, entries } => {
+            let (bit, below) = slot(hash, shift);
+            let index = (*bitmap & below).count_ones() as usize;
+
+            if *bitmap & bit == 0 {
+                entries.insert(index, Entry::Pair(key, value));
+                *bitmap |= bit;
+
+                return true;
+            }
+
+            match &mut entries[index] {
+                Entry::Child(child) => insert_in(child, shift + BITS, hash, key, value),
+                Entry::Pair(k, v) if *k == key => {
+                    *v = value;
+                    false
+                }
+                Entry::Pair(..) => {
+                    let Entry::Pair(old_key, old_value) = entries.remove(index) else {
+                        unreachable!()
+                    };
+
+                    let old_hash = hash_of(&old_key);
+                    entries.insert(index, Entry::Child(pair_node(shift + BITS, (old_hash, old_key, old_value), (hash, key, value))));
+
+                    true
+                }
+            }
+        }
+
+        Node::Collision(pairs) => match pairs.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => {
+                *v = value;
+                false
+            }
+            None => {
+                pairs.push((key, value));
+                true
+            }
+        },
+    }
+}
+
+// A node holding two keys that ended up in the same slot of its parent.
+fn pair_node<K, V>(shift: u32, a: (u64, K, V), b: (u64, K, V)) -> Rc<Node<K, V>> {
+    if shift >= u64::BITS {
+        return Rc::new(Node::Collision(vec![(a.1, a.2), (b.1, b.2)]));
+    }
+
+    let (bit_a, _) = slot(a.0, shift);
+    let (bit_b, _) = slot(b.0, shift);
+
+    if bit_a == bit_b {
+        return Rc::new(Node::Branch {
+            bitmap: bit_a,
+            entries: vec![Entry::Child(pair_node(shift + BITS, a, b))],
+        });
+    }
+
+    let (first, second) = if bit_a < bit_b { (a, b) } else { (b, a) };
+
+    Rc::new(Node::Branch {
+        bitmap: bit_a | bit_b,
+        entries: vec![Entry::Pair(first.1, first.2), Entry::Pair(second.1, second.2)],
+    })
+}
+
+// Only called when the key is known to be present.
+fn remove_from<K: Hash + Eq + Clone, V: Clone>(node: &mut Rc<Node<K, V>>, shift: u32, hash: u64, key: &K) {
+    match Rc::make_mut(node) {
+        Node::Branch { bitmap, entries } => {
+            let (bit, below) = slot(hash, shift);
+            let index = (*bitmap & below).count_ones() as usize;
+
+            match &mut entries[index] {
+                Entry::Pair(..) => {
+                    entries.remove(index);
+                    *bitmap &= !bit;
+                }
+
+                Entry::Child(child) => {
+                    remove_from(child, shift + BITS, hash, key);
+
+                    // pull single remaining keys back up, so lookups stay short
+                    if let Some(entry) = only_pair(child) {
+                        entries[index] = entry;
+                    }
+                }
+            }
+        }
+
+        Node::Collision(pairs) => pairs.retain(|(k, _)| k != key),
+    }
+}
+
+fn only_pair<K: Clone, V: Clone>(node: &Rc<Node<K, V>>) -> Option<Entry<K, V>> {
+    match &**node {
+        Node::Branch { entries, .. } if entries.len() == 1 => match &entries[0] {
+            Entry::Pair(k, v) => Some(Entry::Pair(k.clone(), v.clone())),
+            Entry::Child(_) => None,
+        },
+        Node::Collision(pairs) if pairs.len() == 1 => Some(Entry::Pair(pairs[0].0.clone(), pairs[0].1.clone())),
+
+        _ => None,
+    }
+}
+
+impl<K, V> Clone for Map<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Default for Map<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> FromIterator<(K, V)> for Map<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Map::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Extend<(K, V)> for Map<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert_mut(k, v);
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    stack: Vec<std::slice::Iter<'a, Entry<K, V>>>,
+    pairs: std::slice::Iter<'a, (K, V)>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.pairs.next() {
+                self.remaining -= 1;
+                return Some((k, v));
+            }
+
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                }
+
+                Some(Entry::Pair(k, v)) => {
+                    self.remaining -= 1;
+                    return Some((k, v));
+                }
+
+                Some(Entry::Child(child)) => match &**child {
+                    Node::Branch { entries, .. } => self.stack.push(entries.iter()),
+                    Node::Collision(pairs) => self.pairs = pairs.iter(),
+                },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, hash::{Hash, Hasher}, rc::Rc};
+
+    use super::{hash_of, pair_node, slot, Entry, Map, Node, BITS};
+
+    // Keys with the same first field have the same hash
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    struct Colliding(u8, u32);
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    // Checks the shape of the trie below 'node', returning how many keys it holds and the depth of its collision nodes
+    fn check<K: Hash + Eq, V>(node: &Node<K, V>, shift: u32, is_root: bool, collisions: &mut Vec<u32>) -> usize {
+        match node {
+            Node::Branch { bitmap, entries } => {
+                assert_eq!(bitmap.count_ones() as usize, entries.len());
+
+                if !is_root {
+                    assert!(!entries.is_empty(), "empty branch below the root");
+                    assert!(entries.len() > 1 || matches!(entries[0], Entry::Child(_)), "single key left below the root");
+                }
+
+                let bits = (0..32).map(|i| 1u32 << i).filter(|bit| bitmap & bit != 0);
+
+                bits.zip(entries).map(|(bit, entry)| match entry {
+                    Entry::Pair(k, _) => {
+                        assert_eq!(slot(hash_of(k), shift).0, bit, "key in the wrong slot");
+                        1
+                    }
+                    Entry::Child(child) => check(child, shift + BITS, false, collisions),
+                }).sum()
+            }
+
+            Node::Collision(pairs) => {
+                assert!(shift >= u64::BITS, "collision before using up the hash");
+                assert!(pairs.len() > 1, "collision node with a single key");
+                assert!(pairs.iter().all(|(k, _)| hash_of(k) == hash_of(&pairs[0].0)));
+
+                collisions.push(shift);
+                pairs.len()
+            }
+        }
+    }
+
+    fn collisions<K: Hash + Eq + Clone, V: Clone>(map: &Map<K, V>) -> Vec<u32> {
+        let mut collisions = Vec::new();
+        let count = map.root.as_deref().map_or(0, |root| check(root, 0, true, &mut collisions));
+
+        assert_eq!(count, map.len());
+        assert_eq!(map.iter().count(), map.len());
+
+        collisions
+    }
+
+    #[test]
+    fn matches_a_hash_map() {
+        let mut map = Map::new();
+        let mut expected = HashMap::new();
+        let mut seed = 7u64;
+
+        for _ in 0..20000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let key = (seed >> 33) as u32 % 3000;
+
+            if seed.is_multiple_of(3) {
+                map.remove_mut(&key);
+                expected.remove(&key);
+            } else {
+                map.insert_mut(key, seed);
+                expected.insert(key, seed);
+            }
+
+            assert_eq!(map.len(), expected.len());
+        }
+
+        collisions(&map);
+
+        for key in 0..3000 {
+            assert_eq!(map.get(&key), expected.get(&key));
+        }
+    }
+
+    #[test]
+    fn older_versions_are_unchanged() {
+        let old: Map<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        let inserted = old.insert(5, 50).insert(100, 100);
+        let removed = old.remove(&7);
+
+        assert_eq!((old.len(), old.get(&5), old.get(&7), old.get(&100)), (100, Some(&5), Some(&7), None));
+        assert_eq!((inserted.len(), inserted.get(&5), inserted.get(&100)), (101, Some(&50), Some(&100)));
+        assert_eq!((removed.len(), removed.get(&7)), (99, None));
+    }
+
+    #[test]
+    fn keys_with_the_same_hash_share_a_collision_node() {
+        let mut map = Map::new();
+
+        for i in 0..4 {
+            map.insert_mut(Colliding(1, i), i);
+        }
+
+        map.insert_mut(Colliding(2, 0), 10);
+
+        // the whole hash is used up after 13 levels of 5 bits
+        assert_eq!(collisions(&map), vec![65]);
+        assert_eq!(map.len(), 5);
+
+        map.insert_mut(Colliding(1, 2), 20);
+        assert_eq!((map.len(), map.get(&Colliding(1, 2))), (5, Some(&20)));
+
+        for i in 0..4 {
+            assert!(map.get(&Colliding(1, i)).is_some());
+        }
+
+        assert_eq!(map.get(&Colliding(1, 4)), None);
+
+        map.remove_mut(&Colliding(1, 0));
+        map.remove_mut(&Colliding(1, 4));
+        assert_eq!((map.len(), collisions(&map)), (4, vec![65]));
+
+        // once a single key is left, it's pulled all the way up next to the other one
+        map.remove_mut(&Colliding(1, 1));
+        map.remove_mut(&Colliding(1, 2));
+        assert!(collisions(&map).is_empty());
+        assert_eq!((map.len(), map.get(&Colliding(1, 3)), map.get(&Colliding(2, 0))), (2, Some(&3), Some(&10)));
+
+        map.remove_mut(&Colliding(1, 3));
+        map.remove_mut(&Colliding(2, 0));
+        assert!(map.is_empty() && collisions(&map).is_empty());
+    }
+
+    // Follows branches with a single child, returning how many there were and the node they lead to
+    fn chain<K, V>(mut node: &Node<K, V>) -> (usize, &Node<K, V>) {
+        let mut depth = 0;
+
+        while let Node::Branch { entries, .. } = node {
+            let [Entry::Child(child)] = &entries[..] else {
+                break;
+            };
+
+            node = child;
+            depth += 1;
+        }
+
+        (depth, node)
+    }
+
+    #[test]
+    fn pair_nodes_split_at_the_first_different_bits() {
+        // hashes that only differ in the 9th group of 5 bits
+        let node = pair_node(0, (0, "a", 1), (1 << 40, "b", 2));
+
+        match chain(&node) {
+            (8, Node::Branch { entries, .. }) => assert!(matches!(entries[..], [Entry::Pair("a", 1), Entry::Pair("b", 2)])),
+            _ => panic!("expected the keys 8 levels down"),
+        }
+
+        let same = pair_node(0, (42, "a", 1), (42, "b", 2));
+
+        match chain(&same) {
+            (13, Node::Collision(pairs)) => assert_eq!(pairs, &vec![("a", 1), ("b", 2)]),
+            _ => panic!("expected a collision node 13 levels down"),
+        }
+    }
+
+    #[test]
+    fn removing_pulls_single_keys_up() {
+        let mut map: Map<u32, u32> = (0..2000).map(|i| (i, i)).collect();
+        let shared = map.clone();
+
+        for i in 0..1999 {
+            map.remove_mut(&i);
+            assert_eq!(map.get(&(i + 1)), Some(&(i + 1)));
+        }
+
+        // the last key ends up in the root
+        collisions(&map);
+        assert!(matches!(map.root.as_deref(), Some(Node::Branch { entries, .. }) if matches!(entries[..], [Entry::Pair(1999, _)])));
+        assert!(Rc::strong_count(shared.root.as_ref().unwrap()) == 1 && shared.len() == 2000);
+    }
+}
@@ -0,0 +1,14 @@
+// Immutable collections backing the Lispen collection values.
+// Cloning any of them is O(1), and "modifying" one only copies the path to the
+// changed element, sharing everything else with the original.
+
+mod list;
+mod map;
+mod set;
+mod vector;
+
+pub use list::List;
+pub use map::Map;
+pub use set::Set;
+pub use vector::Vector;
+
@@ -0,0 +1,129 @@
+use std::hash::Hash;
+
+use super::Map;
+
+// A map without values.
+pub struct Set<T> {
+    map: Map<T, ()>,
+}
+
+impl<T: Hash + Eq + Clone> Set<T> {
+    pub fn new() -> Self {
+        Self { map: Map::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.map.contains_key(value)
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.map.ptr_eq(&other.map)
+    }
+
+    pub fn insert(&self, value: T) -> Self {
+        Self { map: self.map.insert(value, ()) }
+    }
+
+    pub fn remove(&self, value: &T) -> Self {
+        Self { map: self.map.remove(value) }
+    }
+
+    pub fn insert_mut(&mut self, value: T) {
+        self.map.insert_mut(value, ());
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &T> {
+        self.map.iter().map(|(k, _)| k)
+    }
+
+    pub fn sorted(&self) -> Vec<&T>
+    where
+        T: Ord,
+    {
+        self.map.sorted().into_iter().map(|(k, _)| k).collect()
+    }
+
+    pub fn is_subset(&self, other: &Set<T>) -> bool {
+        self.len() <= other.len() && self.iter().all(|v| other.contains(v))
+    }
+
+    pub fn union(&self, other: &Set<T>) -> Self {
+        let (mut big, small) = if self.len() >= other.len() { (self.clone(), other) } else { (other.clone(), self) };
+        big.extend(small.iter().cloned());
+        big
+    }
+
+    pub fn intersection(&self, other: &Set<T>) -> Self {
+        self.iter().filter(|v| other.contains(v)).cloned().collect()
+    }
+
+    pub fn difference(&self, other: &Set<T>) -> Self {
+        self.iter().filter(|v| !other.contains(v)).cloned().collect()
+    }
+}
+
+impl<T> Clone for Set<T> {
+    fn clone(&self) -> Self {
+        Self { map: self.map.clone() }
+    }
+}
+
+impl<T: Hash + Eq + Clone> Default for Set<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Hash + Eq + Clone> FromIterator<T> for Set<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Set::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<T: Hash + Eq + Clone> Extend<T> for Set<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for v in iter {
+            self.insert_mut(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Set;
+
+    fn set(items: impl IntoIterator<Item = u32>) -> Set<u32> {
+        items.into_iter().collect()
+    }
+
+    #[test]
+    fn set_operations() {
+        let a = set(0..100);
+        let b = set(50..150);
+
+        assert_eq!(a.union(&b).sorted(), set(0..150).sorted());
+        assert_eq!(a.intersection(&b).sorted(), set(50..100).sorted());
+        assert_eq!(a.difference(&b).sorted(), set(0..50).sorted());
+        assert!(set(10..20).is_subset(&a) && !b.is_subset(&a));
+    }
+
+    #[test]
+    fn older_versions_are_unchanged() {
+        let old = set([1, 2, 3]);
+        let changed = old.insert(4).remove(&1);
+
+        assert!(old.contains(&1) && !old.contains(&4) && old.len() == 3);
+        assert!(changed.contains(&4) && !changed.contains(&1) && changed.len() == 3);
+        assert_eq!(old.insert(2).len(), 3);
+    }
+}
@@ -0,0 +1,302 @@
+use std::rc::Rc;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+// 32-way trie with a separate tail, so indexing and pushing are effectively O(1).
+// The last (up to 32) elements live in 'tail' and are moved into the trie once it fills up.
+pub struct Vector<T> {
+    len: usize,
+    shift: usize,
+    root: Rc<Node<T>>,
+    tail: Rc<Vec<T>>,
+}
+
+#[derive(Clone)]
+enum Node<T> {
+    Branch(Vec<Rc<Node<T>>>),
+    Leaf(Vec<T>),
+}
+
+impl<T: Clone> Vector<T> {
+    pub fn new() -> Self {
+        Self {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(Node::Branch(Vec::new())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+
+        Some(&self.chunk(index)[index & MASK])
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.len == other.len && Rc::ptr_eq(&self.root, &other.root) && Rc::ptr_eq(&self.tail, &other.tail)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            vector: self,
+            index: 0,
+            chunk: &[],
+        }
+    }
+
+    // Returns a new vector with 'value' appended.
+    pub fn push_back(&self, value: T) -> Self {
+        let mut v = self.clone();
+        v.push(value);
+        v
+    }
+
+    // Returns a new vector with the element at 'index' replaced, or None if out of bounds.
+    pub fn update(&self, index: usize, value: T) -> Option<Self> {
+        if index >= self.len {
+            return None;
+        }
+
+        let mut v = self.clone();
+        v.set(index, value);
+        Some(v)
+    }
+
+    // In-place versions, copying only the nodes that are shared with other vectors.
+
+    pub fn push(&mut self, value: T) {
+        if self.len - self.tail_offset() < WIDTH {
+            Rc::make_mut(&mut self.tail).push(value);
+            self.len += 1;
+            return;
+        }
+
+        let tail = std::mem::replace(&mut self.tail, Rc::new(Vec::with_capacity(WIDTH)));
+        let leaf = Rc::new(Node::Leaf(Rc::try_unwrap(tail).unwrap_or_else(|t| (*t).clone())));
+
+        if (self.len >> BITS) > (1 << self.shift) {
+            let old_root = self.root.clone();
+
+            self.root = Rc::new(Node::Branch(vec![old_root, new_path(self.shift, leaf)]));
+            self.shift += BITS;
+        } else {
+            push_tail(self.len, self.shift, &mut self.root, leaf);
+        }
+
+        Rc::make_mut(&mut self.tail).push(value);
+        self.len += 1;
+    }
+
+    fn set(&mut self, index: usize, value: T) {
+        let offset = self.tail_offset();
+
+        if index >= offset {
+            Rc::make_mut(&mut self.tail)[index - offset] = value;
+        } else {
+            set_in(self.shift, &mut self.root, index, value);
+        }
+    }
+
+    // ---
+
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    // The 32 element block containing 'index'
+    fn chunk(&self, index: usize) -> &[T] {
+        if index >= self.tail_offset() {
+            return &self.tail;
+        }
+
+        let mut node = &*self.root;
+        let mut level = self.shift;
+
+        loop {
+            match node {
+                Node::Branch(children) => {
+                    node = &children[(index >> level) & MASK];
+                    level -= BITS;
+                }
+                Node::Leaf(items) => return items,
+            }
+        }
+    }
+}
+
+fn new_path<T>(level: usize, node: Rc<Node<T>>) -> Rc<Node<T>> {
+    if level == 0 {
+        node
+    } else {
+        Rc::new(Node::Branch(vec![new_path(level - BITS, node)]))
+    }
+}
+
+fn push_tail<T: Clone>(len: usize, level: usize, node: &mut Rc<Node<T>>, leaf: Rc<Node<T>>) {
+    let index = ((len - 1) >> level) & MASK;
+
+    if let Node::Branch(children) = Rc::make_mut(node) {
+        if level == BITS {
+            children.push(leaf);
+        } else if index < children.len() {
+            push_tail(len, level - BITS, &mut children[index], leaf);
+        } else {
+            children.push(new_path(level - BITS, leaf));
+        }
+    }
+}
+
+fn set_in<T: Clone>(level: usize, node: &mut Rc<Node<T>>, index: usize, value: T) {
+    match Rc::make_mut(node) {
+        Node::Branch(children) => set_in(level - BITS, &mut children[(index >> level) & MASK], index, value),
+        Node::Leaf(items) => items[index & MASK] = value,
+    }
+}
+
+impl<T> Clone for Vector<T> {
+    fn clone(&self) -> Self {
+        Self {
+            len: self.len,
+            shift: self.shift,
+            root: self.root.clone(),
+            tail: self.tail.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Default for Vector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> FromIterator<T> for Vector<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut v = Vector::new();
+
+        for item in iter {
+            v.push(item);
+        }
+
+        v
+    }
+}
+
+pub struct Iter<'a, T> {
+    vector: &'a Vector<T>,
+    index: usize,
+    chunk: &'a [T],
+}
+
+impl<'a, T: Clone> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.vector.len {
+            return None;
+        }
+
+        if self.index & MASK == 0 {
+            self.chunk = self.vector.chunk(self.index);
+        }
+
+        let item = &self.chunk[self.index & MASK];
+        self.index += 1;
+
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.vector.len - self.index;
+        (len, Some(len))
+    }
+}
+
+impl<T: Clone> ExactSizeIterator for Iter<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Vector, BITS};
+
+    fn check(v: &Vector<usize>, len: usize) {
+        assert_eq!(v.len(), len);
+        assert!((0..len).all(|i| v.get(i) == Some(&i)));
+        assert_eq!(v.get(len), None);
+        assert!(v.iter().copied().eq(0..len));
+    }
+
+    #[test]
+    fn pushes_past_full_roots() {
+        // the root fills up after 32 leaves of 32 elements and a full tail, then after 32 times more
+        for len in [0, 1, 31, 32, 33, 64, 65, 1024, 1055, 1056, 1057, 1088, 1089, 32800, 32801, 33000] {
+            check(&(0..len).collect(), len);
+        }
+
+        let full: Vector<usize> = (0..1056).collect();
+        let grown = full.push_back(1056);
+
+        assert_eq!((full.shift, grown.shift), (BITS, 2 * BITS));
+        check(&full, 1056);
+        check(&grown, 1057);
+
+        let full: Vector<usize> = (0..32800).collect();
+        assert_eq!((full.shift, full.push_back(32800).shift), (2 * BITS, 3 * BITS));
+    }
+
+    #[test]
+    fn older_versions_are_unchanged() {
+        let old: Vector<usize> = (0..2000).collect();
+        let mut changed = old.clone();
+
+        // in the trie, at the end of a leaf, and in the tail
+        for index in [0, 31, 1055, 1999] {
+            changed = changed.update(index, 0).unwrap();
+            assert_eq!(changed.get(index), Some(&0));
+        }
+
+        assert!(old.update(2000, 0).is_none());
+        check(&old, 2000);
+
+        let a = old.push_back(2000);
+        let b = old.push_back(7);
+
+        check(&a, 2001);
+        assert_eq!((b.get(2000), b.len()), (Some(&7), 2001));
+        check(&old, 2000);
+    }
+
+    #[test]
+    fn pushes_after_a_full_root_is_shared() {
+        let full: Vector<usize> = (0..1056).collect();
+        let mut a = full.clone();
+        let mut b = full.clone();
+
+        for i in 1056..2000 {
+            a.push(i);
+            b.push(i);
+        }
+
+        b = b.update(1500, 0).unwrap();
+
+        check(&full, 1056);
+        check(&a, 2000);
+        assert_eq!((b.get(1500), b.get(1501)), (Some(&0), Some(&1501)));
+    }
+}
@@ -0,0 +1,87 @@
+; The prelude: functions written in Lispen itself, defined in every environment.
+; Each definition can only use the native functions and the ones defined above it.
+
+; -- Logic and numbers --
+
+(defn not (x) (if x false true))
+(defn inc (n) (+ n 1))
+(defn dec (n) (- n 1))
+(defn zero? (n) (= n 0))
+(defn max (a b) (if (< a b) b a))
+(defn min (a b) (if (< a b) a b))
+
+; -- Functional combinators --
+
+(defn identity (x) x)
+(defn constantly (x) (fn (_) x))
+(defn compose (f g) (fn (x) (f (g x))))
+(defn partial (f x) (fn (y) (f x y)))
+(defn flip (f) (fn (a b) (f b a)))
+(defn complement (f) (fn (x) (not (f x))))
+
+; -- Collections --
+
+; the elements of any collection, as a list in reverse order
+(defn reverse (coll) (reduce (fn (acc x) (conj acc x)) '() coll))
+
+; the numbers from 'start' up to, but not including, 'end'
+(defn range (start end)
+  (loop [i (dec end) acc '()]
+    (if (< i start) acc (recur (dec i) (conj acc i)))))
+
+(defn last (coll) (first (reverse coll)))
+
+(defn take (n coll)
+  (loop [xs (to-list coll) n n acc '()]
+    (if (zero? n) (reverse acc)
+      (if (empty? xs) (reverse acc) (recur (rest xs) (dec n) (conj acc (first xs)))))))
+
+(defn drop (n coll)
+  (loop [xs (to-list coll) n n]
+    (if (zero? n) xs
+      (if (empty? xs) xs (recur (rest xs) (dec n))))))
+
+(defn concat (a b) (reduce (fn (acc x) (conj acc x)) (to-list b) (reverse a)))
+
+(defn find (pred coll)
+  (loop [xs (to-list coll)]
+    (cond ((empty? xs) nil)
+          ((pred (first xs)) (first xs))
+          (else (recur (rest xs))))))
+
+(defn any? (pred coll)
+  (loop [xs (to-list coll)]
+    (cond ((empty? xs) false)
+          ((pred (first xs)) true)
+          (else (recur (rest xs))))))
+
+(defn all? (pred coll) (not (any? (complement pred) coll)))
+
+(defn index-of (x coll)
+  (loop [xs (to-list coll) i 0]
+    (cond ((empty? xs) nil)
+          ((= (first xs) x) i)
+          (else (recur (rest xs) (inc i))))))
+
+(defn zip (a b)
+  (loop [xs (to-list a) ys (to-list b) acc '()]
+    (if (empty? xs) (reverse acc)
+      (if (empty? ys) (reverse acc) (recur (rest xs) (rest ys) (conj acc [(first xs) (first ys)]))))))
+
+(defn sum (coll) (reduce (fn (a b) (+ a b)) 0 coll))
+(defn product (coll) (reduce (fn (a b) (* a b)) 1 coll))
+
+; -- Strings --
+
+(defn join (sep coll)
+  (let [xs (to-list coll)]
+    (if (empty? xs) ""
+      (reduce (fn (acc x) (+ acc (+ sep (to-string x)))) (to-string (first xs)) (rest xs)))))
+
+(defn string-reverse (s) (join "" (reverse (chars s))))
+
+(defn repeat-string (s n)
+  (loop [n n acc ""] (if (< n 1) acc (recur (dec n) (+ acc s)))))
+
+(defn starts-with? (s prefix) (= (take (count prefix) (chars s)) (chars prefix)))
+(defn ends-with? (s suffix) (starts-with? (string-reverse s) (string-reverse suffix)))
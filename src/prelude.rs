@@ -0,0 +1,50 @@
+use std::cell::RefCell;
+
+use crate::{env, interpreter::{self, Limits, Value}, lexer, parser};
+
+// Functions written in Lispen, defined in every global environment (see 'env::Environment::new').
+const SOURCE: &str = include_str!("prelude.lsp");
+
+thread_local! {
+    // the definitions of the prelude, once it has been run on this thread; new environments copy them instead of running it again
+    static DEFINITIONS: RefCell<Option<Vec<(String, Value)>>> = const { RefCell::new(None) };
+}
+
+// The prelude isn't precompiled: it's parsed and run the first time a thread needs it
+pub fn definitions() -> Vec<(String, Value)> {
+    if let Some(definitions) = DEFINITIONS.with(|definitions| definitions.borrow().clone()) {
+        return definitions;
+    }
+
+    // if it failed, the next environment tries again rather than copying what was defined before the error
+    let Some(definitions) = run() else {
+        return Vec::new();
+    };
+
+    DEFINITIONS.with(|cached| *cached.borrow_mut() = Some(definitions.clone()));
+    definitions
+}
+
+// Runs the prelude in its own scope, so its functions only see the native ones and each other.
+// It needs no capabilities, and gets none, so its functions can't reach anything a sandboxed script couldn't.
+// It isn't part of what scripts evaluate, so it runs without limits.
+fn run() -> Option<Vec<(String, Value)>> {
+    let mut env = env::Environment::from_enclosing(env::Environment::without_prelude(env::Capabilities::NONE));
+
+    // the prelude is part of the interpreter, so any error in it is a bug; it's reported but doesn't stop the interpreter,
+    // whose environments start without the prelude
+    let exprs = match lexer::Lexer::new(SOURCE).lex().and_then(|tokens| parser::Parser::new(tokens).parse()) {
+        Ok(exprs) => exprs,
+        Err(errors) => {
+            eprintln!("{}", interpreter::parse_error(&errors, Some("prelude"), Default::default()));
+            return None;
+        }
+    };
+
+    if let Err(unwind) = interpreter::with_limits(Limits::default(), || interpreter::run(exprs, &mut env)) {
+        interpreter::report(unwind);
+        return None;
+    }
+
+    Some(env.own_variables())
+}
@@ -0,0 +1,225 @@
+use std::{cell::RefCell, collections::HashMap, io::{BufRead, BufReader, Read, Write}, process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio}, rc::Rc};
+
+use crate::{env::EnvVars, interpreter::{Arity, Ctx, Error, Foreign, Value}, persistent};
+
+// Running other programs, for 'process-run' and 'process-spawn'.
+// Programs are started directly with a list of arguments, never through a shell, so arguments are passed as they are.
+
+// What 'process-run' and 'process-spawn' take after the program and its arguments
+#[derive(Default)]
+struct Options {
+    stdin: Option<String>,
+    cwd: Option<String>,
+    env: HashMap<String, String>,
+}
+
+// (process-run program [args] {options}): runs a program to completion, returning a map with its
+// "stdout", "stderr" and "exit-code" (nil if it was killed by a signal)
+pub fn run(ctx: &mut Ctx, args: Vec<Value>, vars: &EnvVars) -> Result<Value, Rc<Error>> {
+    let (mut command, options) = command(ctx, &args, vars)?;
+    let mut child = start(ctx, &mut command)?;
+
+    // the input is written from another thread, so a program printing while it reads can't block on a full pipe;
+    // without any, its standard input is closed right away
+    let stdin = child.stdin.take();
+    let input = options.stdin;
+
+    let writer = std::thread::spawn(move || {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+
+    let output = child.wait_with_output().map_err(|e| io_error(ctx, e))?;
+    let _ = writer.join();
+
+    Ok(result(output.status, lossy(&output.stdout), lossy(&output.stderr)))
+}
+
+// A program started by 'process-spawn', whose pipes can be used while it runs
+struct Process {
+    child: RefCell<Child>,
+    stdin: RefCell<Option<ChildStdin>>,
+    stdout: RefCell<Option<BufReader<ChildStdout>>>,
+    stderr: RefCell<Option<BufReader<ChildStderr>>>,
+}
+
+// (process-spawn program [args] {options}): starts a program without waiting for it, returning a "process" foreign object.
+// Its methods write to its standard input and read from its outputs, and 'wait' returns what 'process-run' would.
+// Given the "stdin" option, that is all its standard input gets, so 'write' can't be used.
+pub fn spawn(ctx: &mut Ctx, args: Vec<Value>, vars: &EnvVars) -> Result<Value, Rc<Error>> {
+    let (mut command, options) = command(ctx, &args, vars)?;
+    let mut child = start(ctx, &mut command)?;
+
+    let mut stdin = child.stdin.take();
+
+    // like in 'run', the input is written from another thread, which closes the standard input once it's done;
+    // written here, a program printing before reading it all would block while nothing reads its output yet
+    if let Some(input) = options.stdin {
+        let pipe = stdin.take();
+
+        std::thread::spawn(move || {
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.write_all(input.as_bytes());
+            }
+        });
+    }
+
+    let process = Process {
+        stdin: RefCell::new(stdin),
+        stdout: RefCell::new(child.stdout.take().map(BufReader::new)),
+        stderr: RefCell::new(child.stderr.take().map(BufReader::new)),
+        child: RefCell::new(child),
+    };
+
+    let pid = process.child.borrow().id();
+
+    Ok(Foreign::new("process", process)
+        .with_display(move |_: &Process| format!("<process {}>", pid))
+        .with_method("pid", Arity::Exact(0), "The id of the process", |_, p: &Process, _| {
+            Ok(Value::Number(p.child.borrow().id() as f64))
+        })
+        .with_method("write", Arity::Exact(1), "Writes a string to the standard input of the process", |ctx, p: &Process, args| {
+            write(ctx, p, ctx.arg(&args, 0)?)
+        })
+        .with_method("write-line", Arity::Exact(1), "Writes a string and a new line to the standard input of the process", |ctx, p: &Process, args| {
+            write(ctx, p, ctx.arg::<String>(&args, 0)? + "\n")
+        })
+        .with_method("close-stdin", Arity::Exact(0), "Closes the standard input of the process, so it sees its end", |_, p: &Process, _| {
+            p.stdin.borrow_mut().take();
+            Ok(Value::Nil)
+        })
+        .with_method("read-line", Arity::Exact(0), "The next line of the standard output, or nil at its end", |ctx, p: &Process, _| {
+            read_line(ctx, &p.stdout)
+        })
+        .with_method("read-error-line", Arity::Exact(0), "The next line of the standard error, or nil at its end", |ctx, p: &Process, _| {
+            read_line(ctx, &p.stderr)
+        })
+        .with_method("kill", Arity::Exact(0), "Kills the process", |ctx, p: &Process, _| {
+            p.child.borrow_mut().kill().map_err(|e| io_error(ctx, e))?;
+            Ok(Value::Nil)
+        })
+        .with_method("wait", Arity::Exact(0), "Closes the standard input and waits for the process to end, returning the rest of its output and its exit code", |ctx, p: &Process, _| {
+            p.stdin.borrow_mut().take();
+
+            // the standard error is read on another thread, so a process filling it while its standard output is read can't block
+            let stderr = p.stderr.borrow_mut().take();
+            let reader = std::thread::spawn(move || read_rest(stderr));
+
+            let stdout = read_rest(p.stdout.borrow_mut().take());
+            let stderr = reader.join().unwrap_or_default();
+
+            let status = p.child.borrow_mut().wait().map_err(|e| io_error(ctx, e))?;
+            Ok(result(status, stdout, stderr))
+        })
+        .into_value())
+}
+
+// The program, arguments and options common to 'process-run' and 'process-spawn'.
+// The program gets the environment variables of the interpreter, with those of the "env" option on top.
+fn command(ctx: &Ctx, args: &[Value], vars: &EnvVars) -> Result<(Command, Options), Rc<Error>> {
+    let program: String = ctx.arg(args, 0)?;
+    let arguments: Option<Vec<String>> = ctx.arg(args, 1)?;
+    let settings: Option<HashMap<String, Value>> = ctx.arg(args, 2)?;
+
+    let mut options = Options::default();
+
+    for (key, value) in settings.unwrap_or_default() {
+        let expected = match key.as_str() {
+            "stdin" => "a string",
+            "cwd" => "a string",
+            "env" => "a map from strings to strings",
+            _ => { return ctx.error("value-error", &format!("Function '{}' got an unknown option '{}'; expected \"stdin\", \"cwd\" or \"env\"", ctx.name(), key)); }
+        };
+
+        let valid = match (key.as_str(), value) {
+            ("stdin", Value::String(s)) => { options.stdin = Some(s); true }
+            ("cwd", Value::String(s)) => { options.cwd = Some(s); true }
+            ("env", Value::Map(m)) => m.iter().all(|(k, v)| match (k, v) {
+                (Value::String(k), Value::String(v)) => { options.env.insert(k.clone(), v.clone()); true }
+                _ => false,
+            }),
+            _ => false,
+        };
+
+        if !valid {
+            return ctx.error("type-error", &format!("Function '{}' expected {} for the option '{}'", ctx.name(), expected, key));
+        }
+    }
+
+    let mut command = Command::new(&program);
+    vars.apply(&mut command);
+    command.args(arguments.unwrap_or_default()).envs(&options.env);
+
+    if let Some(cwd) = &options.cwd {
+        command.current_dir(cwd);
+    }
+
+    Ok((command, options))
+}
+
+fn start(ctx: &Ctx, command: &mut Command) -> Result<Child, Rc<Error>> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| ctx.raise("io-error", &format!("Couldn't run '{}': {}", command.get_program().to_string_lossy(), e)))
+}
+
+fn write(ctx: &Ctx, process: &Process, text: String) -> Result<Value, Rc<Error>> {
+    match process.stdin.borrow_mut().as_mut() {
+        Some(stdin) => stdin.write_all(text.as_bytes()).and_then(|_| stdin.flush()).map_err(|e| io_error(ctx, e))?,
+        None => { return ctx.error("io-error", "The standard input of the process was closed, or given with the \"stdin\" option"); }
+    }
+
+    Ok(Value::Nil)
+}
+
+fn read_line<R: Read>(ctx: &Ctx, pipe: &RefCell<Option<BufReader<R>>>) -> Result<Value, Rc<Error>> {
+    let mut pipe = pipe.borrow_mut();
+
+    let Some(reader) = pipe.as_mut() else {
+        return Ok(Value::Nil);
+    };
+
+    let mut line = String::new();
+
+    if reader.read_line(&mut line).map_err(|e| io_error(ctx, e))? == 0 {
+        return Ok(Value::Nil);
+    }
+
+    let trimmed = line.strip_suffix('\n').map(|l| l.strip_suffix('\r').unwrap_or(l)).unwrap_or(&line);
+    Ok(Value::String(trimmed.to_owned()))
+}
+
+fn read_rest<R: Read>(pipe: Option<BufReader<R>>) -> String {
+    let mut bytes = Vec::new();
+
+    if let Some(mut reader) = pipe {
+        let _ = reader.read_to_end(&mut bytes);
+    }
+
+    lossy(&bytes)
+}
+
+fn result(status: ExitStatus, stdout: String, stderr: String) -> Value {
+    let exit_code = match status.code() {
+        Some(code) => Value::Number(code as f64),
+        None => Value::Nil,
+    };
+
+    Value::Map(persistent::Map::from_iter([
+        (Value::String("stdout".to_owned()), Value::String(stdout)),
+        (Value::String("stderr".to_owned()), Value::String(stderr)),
+        (Value::String("exit-code".to_owned()), exit_code),
+    ]))
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn io_error(ctx: &Ctx, e: std::io::Error) -> Rc<Error> {
+    ctx.raise("io-error", &format!("Function '{}' failed: {}", ctx.name(), e))
+}
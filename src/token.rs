@@ -10,6 +10,13 @@ pub enum TokenKind {
   
   LParen,
   RParen,
+  LBrace,
+  RBrace,
+  LBracket,
+  RBracket,
+  HashLBrace,
+  // '#name{', opening a record literal; the lexeme is the struct name
+  RecordLBrace,
 }
 
 #[derive(Debug, Clone)]